@@ -1,49 +1,91 @@
 mod block;
+mod bot;
+mod bot_api;
+mod command;
 mod constants;
+mod custom_map;
+mod diff;
+pub mod engine;
+mod map_generator;
+mod playback;
 mod player_in_room;
+mod replay;
 mod room;
+mod rules;
+mod server_room;
+mod view;
 
 use axum::{http::StatusCode, Json};
 use block::Block;
-use constants::{MAX_TEAM_NUM, SPEED_OPTIONS};
-use player_in_room::{MinifiedPlayer, PlayerInRoom};
-use prisma_client_rust::QueryError;
+use bot::BotDifficulty;
+use command::{Command, Move};
+use constants::{
+    AFK_WARNING_TURNS, COLOR_ARR, MAX_TEAM_NUM, RECONNECT_GRACE_SECS, REPLAY_FLUSH_INTERVAL,
+    REPLAY_SPEED_OPTIONS, SPEED_OPTIONS, START_COUNTDOWN_SECS,
+};
+use playback::{Playback, PlaybackCommand};
+use player_in_room::MinifiedPlayer;
+use prisma_client_rust::{Direction, QueryError};
 use querystring::{querify, QueryParams};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use room::{GameOptions, MinifiedRoom, Room};
+use replay::{parse_records, ReplayRecorder, ReplayRecords};
+use room::{GameOptions, MinifiedRoom, PublicRoom, RoomState, TurnEvent};
 use serde::Serialize;
 use serde_json::{json, Value};
+use server_room::{Resync, Seat, ServerRoom, Session};
 use socketioxide::{
     extract::{Data, SocketRef, State},
     socket::Sid,
+    SocketIo,
 };
 use std::{
     borrow::Borrow,
     collections::{BTreeMap, VecDeque},
     env,
-    sync::Arc,
+    sync::{Arc, OnceLock},
+    time::Duration,
 };
 use tokio::sync::RwLock;
-use tracing::info;
+use tracing::{info, warn};
+
+use crate::prisma::{
+    custom_map_data, map_diff, map_keyframe, message, player,
+    replay::{self as stored_replay, map_width},
+    PrismaClient,
+};
 
-use crate::prisma::{custom_map_data, player, replay::map_width, PrismaClient};
+pub use bot_api::{handle_bot_connection, BOT_NAMESPACE};
+pub use playback::PlaybackStore;
+pub use replay::{replay_snapshot, ReplaySnapshot};
 
-pub type RoomPool = BTreeMap<String, Room>;
+pub type RoomPool = BTreeMap<String, ServerRoom>;
 pub static MAX_ROOM_COUNT: usize = 5;
 
 #[derive(Clone, Default)]
 pub struct RoomPoolStore {
     pub pool: Arc<RwLock<RoomPool>>,
+    pub io: Arc<OnceLock<SocketIo>>,
 }
 
 pub type RoomPoolState = State<RoomPoolStore>;
 
 impl RoomPoolStore {
+    // The socket.io handle only exists once the layer is built, so it is bound afterwards.
+    // Game loops use it to push updates to a room without holding a socket.
+    pub fn bind_io(&self, io: SocketIo) {
+        let _ = self.io.set(io);
+    }
+
     pub async fn get(&self) -> RoomPool {
         let binding = self.pool.read().await;
         binding.clone()
     }
 
+    pub async fn public_room(&self, room_id: &str) -> Option<PublicRoom> {
+        let binding = self.pool.read().await;
+        binding.get(room_id).map(|room| room.to_public())
+    }
+
     pub async fn serialize(&self) -> Vec<MinifiedRoom> {
         let binding = self.pool.read().await;
 
@@ -58,29 +100,7 @@ impl RoomPoolStore {
         if binding.len() >= MAX_ROOM_COUNT {
             return Err("Room pool length exceeded.");
         }
-        let room = Room {
-            force_start_num: 0,
-            game_options: GameOptions {
-                room_name: "Untitled".to_string(),
-                map_id: "".to_string(),
-                map_name: "".to_string(),
-                max_players: 8,
-                game_speed: 1.0,
-                map_width: 0.5,
-                map_height: 0.5,
-                mountain: 0.5,
-                city: 0.5,
-                swamp: 0.0,
-                fog_of_war: true,
-                death_spectating: true,
-                reveal_king: false,
-                warring_state: false,
-            },
-            game_started: false,
-            map_generated: false,
-            players: Vec::new(),
-            map: Vec::new(),
-        };
+        let room = ServerRoom::new(GameOptions::default());
         let pool = binding.insert(room_id, room);
         Ok(())
     }
@@ -98,31 +118,37 @@ impl RoomPoolStore {
         socket_id: Sid,
         room_id: String,
         player: player::Data,
-    ) -> Result<PlayerInRoom, &'static str> {
+        compact: bool,
+    ) -> Result<Seat, &'static str> {
         let mut binding = self.pool.write().await;
 
         match binding.get_mut(&room_id) {
             Some(room) => {
                 let max_players = (*room).game_options.max_players;
                 let player_count = (*room).players.len();
+                if room.players.iter().any(|x| x.player_id == player.id) {
+                    return Err("Already in this room.");
+                }
                 if max_players > player_count {
-                    let mut new_player = PlayerInRoom::default();
+                    let mut new_player = Seat::default();
 
                     new_player.username = player.username;
                     new_player.player_id = player.id;
-                    new_player.socket_id = socket_id;
+                    new_player.session.socket_id = socket_id;
+                    new_player.session.compact = compact;
 
-                    for i in (1..player_count) {
-                        if None == (*room).players.iter().find(|x| x.color == i) {
-                            new_player.color = i;
-                            break;
+                    match (room.free_color(), room.free_team()) {
+                        (Some(color), Some(team)) => {
+                            new_player.color = color;
+                            new_player.team = team;
                         }
+                        _ => return Err("Room is full."),
                     }
-                    for i in (1..player_count) {
-                        if None == (*room).players.iter().find(|x| x.team == i) {
-                            new_player.team = i;
-                            break;
-                        }
+                    // The first player to join, bots aside, runs the room.
+                    new_player.is_room_host = !room.players.iter().any(|x| x.is_room_host);
+                    // Late joiners watch the running game.
+                    if !room.in_lobby() {
+                        new_player.set_spectate();
                     }
 
                     (*room).players.push(new_player.clone());
@@ -136,6 +162,87 @@ impl RoomPoolStore {
         }
     }
 
+    // Bots are always ready and play from the server, so they get no socket.
+    pub async fn add_bot(
+        &self,
+        socket_id: Sid,
+        room_id: String,
+        difficulty: BotDifficulty,
+    ) -> Result<MinifiedPlayer, &'static str> {
+        let mut binding = self.pool.write().await;
+
+        match binding.get_mut(&room_id) {
+            Some(room) => {
+                if !room
+                    .players
+                    .iter()
+                    .any(|x| x.session.socket_id == socket_id && x.is_room_host)
+                {
+                    return Err("Permission denied.");
+                }
+                if !room.in_lobby() {
+                    return Err("Game already started.");
+                }
+                if room.players.len() >= room.game_options.max_players {
+                    return Err("Room is full.");
+                }
+                let (Some(color), Some(team)) = (room.free_color(), room.free_team()) else {
+                    return Err("Room is full.");
+                };
+
+                let mut bot = Seat::default();
+                bot.player_id = format!("bot-{}", generate_random_string(16));
+                bot.username = format!("[Bot] {:?} {}", difficulty, color);
+                bot.color = color;
+                bot.team = team;
+                bot.force_start = true;
+                bot.bot = Some(difficulty);
+                room.players.push(bot.clone());
+                room.update_votes();
+
+                return Ok(bot.minify());
+            }
+            None => return Err("Room not found."),
+        }
+    }
+
+    pub async fn remove_bot(
+        &self,
+        socket_id: Sid,
+        room_id: String,
+        username: String,
+    ) -> Result<MinifiedPlayer, &'static str> {
+        let mut binding = self.pool.write().await;
+
+        match binding.get_mut(&room_id) {
+            Some(room) => {
+                if !room
+                    .players
+                    .iter()
+                    .any(|x| x.session.socket_id == socket_id && x.is_room_host)
+                {
+                    return Err("Permission denied.");
+                }
+                if !room.in_lobby() {
+                    return Err("Game already started.");
+                }
+                match room
+                    .players
+                    .iter()
+                    .position(|x| x.bot.is_some() && x.username == username)
+                {
+                    Some(index) => {
+                        let bot = room.players.remove(index);
+                        room.update_votes();
+                        return Ok(bot.minify());
+                    }
+                    None => return Err("Bot not found."),
+                }
+            }
+            None => return Err("Room not found."),
+        }
+    }
+
     pub async fn query_player(
         &self,
         socket_id: Sid,
@@ -145,7 +252,11 @@ impl RoomPoolStore {
 
         match binding.get(&room_id) {
             Some(room) => {
-                match room.players.iter().find(|x| x.socket_id == socket_id) {
+                match room
+                    .players
+                    .iter()
+                    .find(|x| x.session.socket_id == socket_id)
+                {
                     Some(player) => return Ok(player.minify()),
                     None => return Err("Player not found."),
                 };
@@ -154,16 +265,86 @@ impl RoomPoolStore {
         }
     }
 
-    pub async fn remove_player(&self, socket_id: Sid) {
+    // Chat sent during a game is kept for the replay.
+    pub async fn record_message(&self, socket_id: Sid, room_id: String, content: String) {
         let mut binding = self.pool.write().await;
 
-        for (_, room) in binding.iter_mut() {
-            for i in (0..(room.players.len() - 1)) {
-                if room.players[i].socket_id == socket_id {
-                    room.players.remove(i);
+        if let Some(room) = binding.get_mut(&room_id) {
+            let turn = room.turn;
+            let Some(sender) = room
+                .players
+                .iter()
+                .find(|x| x.session.socket_id == socket_id)
+            else {
+                return;
+            };
+            let (player_id, minified) = (sender.player_id.clone(), sender.minify());
+            if let Some(recorder) = room.recorder.as_mut() {
+                recorder.record_message(turn, player_id, content.clone());
+            }
+            room.push_message(minified, content);
+        }
+    }
+
+    // Rebinds a player whose old socket is gone to their new one, browsers and bots only
+    // taking back seats of their own kind. Returns the room to rejoin with its full state.
+    pub async fn reconnect_player(
+        &self,
+        socket_id: Sid,
+        player_id: &str,
+        compact: bool,
+    ) -> Option<Resync> {
+        let io = self.io.get()?;
+        let mut binding = self.pool.write().await;
+
+        for (room_id, room) in binding.iter_mut() {
+            let Some(index) = room.players.iter().position(|x| {
+                x.player_id == player_id
+                    && x.session.compact == compact
+                    && session_socket(io, &x.session).is_none()
+            }) else {
+                continue;
+            };
+            room.players[index].session.socket_id = socket_id;
+            return Some(room.resync(room_id.clone(), index));
+        }
+        None
+    }
+
+    // Players leaving a running game are surrendered instead, and dropped from the room
+    // once the game is over. A lobby that lost a player may start or stop its countdown.
+    pub async fn remove_player(&self, socket_id: Sid, db: Arc<PrismaClient>) {
+        let mut lobbies = Vec::new();
+        {
+            let mut binding = self.pool.write().await;
+
+            for (room_id, room) in binding.iter_mut() {
+                let Some(index) = room
+                    .players
+                    .iter()
+                    .position(|x| x.session.socket_id == socket_id)
+                else {
+                    continue;
+                };
+                if room.in_lobby() {
+                    room.players.remove(index);
+                    room.ensure_host();
+                    lobbies.push(room_id.clone());
+                    continue;
+                }
+
+                let player = &room.players[index];
+                if !player.is_dead && !player.is_spectating() {
+                    room.surrender(index);
+                    self.announce(room_id, "message:surrender", room.players[index].minify());
                 }
             }
         }
+
+        for room_id in lobbies {
+            self.check_auto_start(room_id.clone(), db.clone()).await;
+            broadcast_room_update(self, room_id).await;
+        }
     }
 
     pub async fn player_force_start(
@@ -175,18 +356,256 @@ impl RoomPoolStore {
 
         match binding.get_mut(&room_id) {
             Some(room) => {
-                match room.players.iter_mut().find(|x| x.socket_id == socket_id) {
+                if !room.in_lobby() {
+                    return Err("Game already started.");
+                }
+                match room
+                    .players
+                    .iter_mut()
+                    .find(|x| x.session.socket_id == socket_id)
+                {
                     Some(player) => {
+                        if player.is_spectating() {
+                            return Err("Spectators can't vote.");
+                        }
                         player.force_start = !player.force_start;
-                        return Ok(());
                     }
                     None => return Err("Player not found."),
                 };
+                room.update_votes();
+                return Ok(());
+            }
+            None => return Err("Room not found."),
+        }
+    }
+
+    // Starts a countdown once the room may start, and drops it when that's no longer the
+    // case. The game itself starts when the countdown runs out.
+    pub async fn check_auto_start(&self, room_id: String, db: Arc<PrismaClient>) {
+        let mut binding = self.pool.write().await;
+        let Some(room) = binding.get_mut(&room_id) else {
+            return;
+        };
+        if !room.in_lobby() {
+            return;
+        }
+
+        match (room.update_votes(), room.state) {
+            (true, RoomState::Lobby) => {
+                let _ = room.set_state(RoomState::Countdown);
+                room.countdown_token += 1;
+                self.announce(&room_id, "countdown", START_COUNTDOWN_SECS);
+                self.announce(&room_id, "room_state", room.state);
+                tokio::spawn(run_countdown(
+                    self.clone(),
+                    db,
+                    room_id,
+                    room.countdown_token,
+                ));
+            }
+            (false, RoomState::Countdown) => {
+                let _ = room.set_state(RoomState::Lobby);
+                self.announce(&room_id, "countdown:cancel", ());
+                self.announce(&room_id, "room_state", room.state);
+            }
+            _ => {}
+        }
+    }
+
+    // Room wide events reach browsers in the room and bots, who join a room of the same
+    // name on the /bot namespace.
+    pub fn announce<T: Serialize>(&self, room_id: &str, event: &str, data: T) {
+        let (Some(io), Ok(data)) = (self.io.get(), serde_json::to_value(data)) else {
+            return;
+        };
+        let _ = io
+            .within(room_id.to_string())
+            .emit(event.to_string(), data.clone());
+        if let Some(bots) = io.of(BOT_NAMESPACE) {
+            let _ = bots
+                .within(room_id.to_string())
+                .emit(event.to_string(), data);
+        }
+    }
+
+    // Votes of human players are dropped too, so the countdown doesn't start again at once.
+    pub async fn cancel_countdown(
+        &self,
+        socket_id: Sid,
+        room_id: String,
+    ) -> Result<(), &'static str> {
+        let mut binding = self.pool.write().await;
+
+        match binding.get_mut(&room_id) {
+            Some(room) => {
+                if !room
+                    .players
+                    .iter()
+                    .any(|x| x.session.socket_id == socket_id && x.is_room_host)
+                {
+                    return Err("Permission denied.");
+                }
+                if room.state != RoomState::Countdown {
+                    return Err("No countdown running.");
+                }
+                room.set_state(RoomState::Lobby)?;
+                self.announce(&room_id, "room_state", room.state);
+                for player in room.players.iter_mut().filter(|x| x.bot.is_none()) {
+                    player.force_start = false;
+                }
+                room.update_votes();
+                return Ok(());
+            }
+            None => return Err("Room not found."),
+        }
+    }
+
+    pub async fn start_game(
+        &self,
+        room_id: String,
+        db: Arc<PrismaClient>,
+    ) -> Result<(), &'static str> {
+        let map_id = match self.pool.read().await.get(&room_id) {
+            Some(room) => room.game_options.map_id.clone(),
+            None => return Err("Room not found."),
+        };
+
+        let custom_map = if map_id.is_empty() {
+            None
+        } else {
+            match db
+                .custom_map_data()
+                .find_unique(custom_map_data::id::equals(map_id))
+                .exec()
+                .await
+            {
+                Ok(Some(data)) => Some(data),
+                _ => return Err("Custom map not found."),
+            }
+        };
+
+        let mut binding = self.pool.write().await;
+
+        match binding.get_mut(&room_id) {
+            Some(room) => {
+                if !room.in_lobby() {
+                    return Err("Game already started.");
+                }
+                match custom_map {
+                    Some(data) => room.load_custom_map(
+                        &data.map_tiles_data,
+                        data.width as usize,
+                        data.height as usize,
+                    )?,
+                    None => room.generate_map()?,
+                }
+                room.set_state(RoomState::Playing)?;
+                room.turn = 0;
+                // The first update of a game patches a blank map, and the AFK check counts
+                // from turn 0 again.
+                for player in room.players.iter_mut() {
+                    player.session.last_view.clear();
+                    player.last_operate_turn = 0;
+                }
+                self.announce(&room_id, "room_state", room.state);
+
+                tokio::spawn(run_game_loop(
+                    self.clone(),
+                    db,
+                    room_id.clone(),
+                    room.turn_interval(),
+                ));
+                info!("Game started in room {}.", room_id);
+                return Ok(());
             }
             None => return Err("Room not found."),
         }
     }
 
+    pub async fn queue_move(
+        &self,
+        socket_id: Sid,
+        room_id: String,
+        next: Move,
+    ) -> Result<VecDeque<Move>, &'static str> {
+        let mut binding = self.pool.write().await;
+
+        match binding.get_mut(&room_id) {
+            Some(room) if room.state != RoomState::Playing => return Err("Game not started."),
+            Some(room) => match room
+                .players
+                .iter()
+                .position(|x| x.session.socket_id == socket_id)
+            {
+                Some(index) => {
+                    room.apply_command(index, Command::Move(next))?;
+                    return Ok(room.players[index].queue.clone());
+                }
+                None => return Err("Player not found."),
+            },
+            None => return Err("Room not found."),
+        }
+    }
+
+    // Returns the surrendered player and whether they may stay to spectate.
+    pub async fn surrender(
+        &self,
+        socket_id: Sid,
+        room_id: String,
+    ) -> Result<(MinifiedPlayer, bool), &'static str> {
+        let mut binding = self.pool.write().await;
+
+        match binding.get_mut(&room_id) {
+            Some(room) if room.state != RoomState::Playing => return Err("Game not started."),
+            Some(room) => match room
+                .players
+                .iter()
+                .position(|x| x.session.socket_id == socket_id)
+            {
+                Some(index) => {
+                    room.apply_command(index, Command::Surrender)?;
+                    return Ok((
+                        room.players[index].minify(),
+                        room.game_options.death_spectating,
+                    ));
+                }
+                None => return Err("Player not found."),
+            },
+            None => return Err("Room not found."),
+        }
+    }
+
+    // Drops the whole queue, or only the last queued move when `clear` is false.
+    pub async fn unqueue_moves(
+        &self,
+        socket_id: Sid,
+        room_id: String,
+        clear: bool,
+    ) -> Result<VecDeque<Move>, &'static str> {
+        let mut binding = self.pool.write().await;
+        let command = if clear {
+            Command::ClearQueue
+        } else {
+            Command::PopQueue
+        };
+
+        match binding.get_mut(&room_id) {
+            Some(room) if room.state != RoomState::Playing => return Err("Game not started."),
+            Some(room) => match room
+                .players
+                .iter()
+                .position(|x| x.session.socket_id == socket_id)
+            {
+                Some(index) => {
+                    room.apply_command(index, command)?;
+                    return Ok(room.players[index].queue.clone());
+                }
+                None => return Err("Player not found."),
+            },
+            None => return Err("Room not found."),
+        }
+    }
+
     pub async fn change_player_team(
         &self,
         socket_id: Sid,
@@ -195,7 +614,8 @@ impl RoomPoolStore {
     ) -> Result<MinifiedPlayer, &'static str> {
         match team.as_u64() {
             Some(team) => {
-                if team as usize > MAX_TEAM_NUM + 1 {
+                // Team 0 is the neutral land's, players can't join it.
+                if team == 0 || team as usize > MAX_TEAM_NUM + 1 {
                     return Err("Invalid team.");
                 }
 
@@ -203,17 +623,25 @@ impl RoomPoolStore {
 
                 match binding.get_mut(&room_id) {
                     Some(room) => {
-                        match room.players.iter_mut().find(|x| x.socket_id == socket_id) {
+                        if !room.in_lobby() {
+                            return Err("Game already started.");
+                        }
+                        match room
+                            .players
+                            .iter_mut()
+                            .find(|x| x.session.socket_id == socket_id)
+                        {
                             Some(player) => {
                                 if team as usize != player.team {
                                     player.team = team.clone() as usize;
 
-                                    if player.is_spectating() && player.force_start {
+                                    if player.is_spectating() {
                                         player.force_start = false;
-                                        room.force_start_num -= 1;
                                     }
                                 }
-                                return Ok(player.minify());
+                                let player = player.minify();
+                                room.update_votes();
+                                return Ok(player);
                             }
                             None => return Err("Player not found."),
                         };
@@ -225,18 +653,69 @@ impl RoomPoolStore {
         }
     }
 
+    pub async fn change_player_color(
+        &self,
+        socket_id: Sid,
+        room_id: String,
+        color: Value,
+    ) -> Result<MinifiedPlayer, &'static str> {
+        match color.as_u64() {
+            Some(color) => {
+                // 0 is the neutral gray, never a player color.
+                let color = color as usize;
+                if color == 0 || color >= COLOR_ARR.len() {
+                    return Err("Invalid color.");
+                }
+
+                let mut binding = self.pool.write().await;
+
+                match binding.get_mut(&room_id) {
+                    Some(room) => {
+                        if !room.in_lobby() {
+                            return Err("Game already started.");
+                        }
+                        if room
+                            .players
+                            .iter()
+                            .any(|x| x.color == color && x.session.socket_id != socket_id)
+                        {
+                            return Err("Color already taken.");
+                        }
+                        match room
+                            .players
+                            .iter_mut()
+                            .find(|x| x.session.socket_id == socket_id)
+                        {
+                            Some(player) => {
+                                player.color = color;
+                                return Ok(player.minify());
+                            }
+                            None => return Err("Player not found."),
+                        };
+                    }
+                    None => return Err("Room not found."),
+                }
+            }
+            None => return Err("Invalid color."),
+        }
+    }
+
     pub async fn change_player_host(
         &self,
         socket_id: Sid,
         room_id: String,
-        player_id: String,
+        color: usize,
     ) -> Result<(MinifiedPlayer, MinifiedPlayer), &'static str> {
         let mut binding = self.pool.write().await;
 
         match binding.get_mut(&room_id) {
             Some(room) => {
                 let mut from_player = MinifiedPlayer::default();
-                match room.players.iter_mut().find(|x| x.socket_id == socket_id) {
+                match room
+                    .players
+                    .iter_mut()
+                    .find(|x| x.session.socket_id == socket_id)
+                {
                     Some(player) => {
                         if (player.is_room_host) {
                             from_player = player.minify();
@@ -249,7 +728,8 @@ impl RoomPoolStore {
                 }
 
                 let mut to_player = MinifiedPlayer::default();
-                match room.players.iter_mut().find(|x| x.player_id == player_id) {
+                // Usernames aren't unique, colors are within a room.
+                match room.players.iter_mut().find(|x| x.color == color) {
                     Some(player) => {
                         if (player.is_room_host) {
                             return Err("Current player is host.");
@@ -260,9 +740,11 @@ impl RoomPoolStore {
                     }
                     None => {
                         // Now the room has no host :(
-                        // We should make the first player host.
-                        room.players[0].is_room_host = true;
-                        to_player = room.players[0].minify();
+                        // Hand it to the first human player, bots can't run a room.
+                        room.ensure_host();
+                        if let Some(host) = room.players.iter().find(|x| x.is_room_host) {
+                            to_player = host.minify();
+                        }
                     }
                 }
 
@@ -283,10 +765,15 @@ impl RoomPoolStore {
         let mut binding = self.pool.write().await;
         match binding.get_mut(&room_id) {
             Some(room) => {
+                if !room.in_lobby() {
+                    return Err("Game already started.".to_string());
+                }
+                // Minified right away, the options can't change while a player is borrowed.
                 match room
                     .players
                     .iter()
-                    .find(|x| x.socket_id == socket_id && x.is_room_host)
+                    .find(|x| x.session.socket_id == socket_id && x.is_room_host)
+                    .map(|x| x.minify())
                 {
                     Some(player) => {
                         match prop.as_str() {
@@ -297,6 +784,11 @@ impl RoomPoolStore {
                                 None => return Err(format!("Invalid {prop}.")),
                             },
                             "map_id" => match val.as_str() {
+                                // An empty id goes back to a random map.
+                                Some("") => {
+                                    room.game_options.map_id = String::new();
+                                    room.game_options.map_name = String::new();
+                                }
                                 Some(map_id) => {
                                     match db
                                         .custom_map_data()
@@ -306,12 +798,11 @@ impl RoomPoolStore {
                                         .exec()
                                         .await
                                     {
-                                        Ok(data) => {
-                                            let tmp = data.unwrap().clone();
+                                        Ok(Some(data)) => {
                                             room.game_options.map_id = map_id.to_string();
-                                            room.game_options.map_name = tmp.name;
+                                            room.game_options.map_name = data.name;
                                         }
-                                        Err(_) => return Err(format!("Invalid {prop}.")),
+                                        _ => return Err(format!("Invalid {prop}.")),
                                     }
                                 }
                                 None => return Err(format!("Invalid {prop}.")),
@@ -398,9 +889,34 @@ impl RoomPoolStore {
                                 }
                                 None => return Err(format!("Invalid {prop}.")),
                             },
+                            "neutral_surrender" => match val.as_bool() {
+                                Some(neutral_surrender) => {
+                                    room.game_options.neutral_surrender = neutral_surrender;
+                                }
+                                None => return Err(format!("Invalid {prop}.")),
+                            },
+                            "afk_turns" => match val.as_u64() {
+                                Some(afk_turns) => {
+                                    if afk_turns == 0 || afk_turns > AFK_WARNING_TURNS as u64 {
+                                        room.game_options.afk_turns = afk_turns as u32;
+                                    }
+                                }
+                                None => return Err(format!("Invalid {prop}.")),
+                            },
+                            "seed" => match val {
+                                Value::Null => {
+                                    room.game_options.seed = None;
+                                }
+                                _ => match val.as_u64() {
+                                    Some(seed) => {
+                                        room.game_options.seed = Some(seed);
+                                    }
+                                    None => return Err(format!("Invalid {prop}.")),
+                                },
+                            },
                             _ => return Err("Invalid key.".to_string()),
                         }
-                        return Ok(player.minify());
+                        return Ok(player);
                     }
                     None => return Err("Permission denied.".to_string()),
                 }
@@ -435,6 +951,16 @@ pub async fn handle_connection(
             info!("{} ({}) successfully logged in.", username, player_id);
             let _ = socket.emit("login:success", ());
 
+            // A player who dropped out of a room picks up where they left off.
+            if let Some(resync) = room_pool
+                .reconnect_player(socket.id, &player.id, false)
+                .await
+            {
+                info!("{} reconnected.", username);
+                let _ = socket.join(resync.room_id.clone());
+                let _ = socket.emit("resync", resync);
+            }
+
             socket.on(
                 "rooms",
                 |socket: SocketRef, room_pool: RoomPoolState| async move {
@@ -461,24 +987,28 @@ pub async fn handle_connection(
                 "join_room",
                 |socket: SocketRef,
                  Data::<String>(room_id): Data<String>,
+                 db: State<Arc<PrismaClient>>,
                  room_pool: RoomPoolState| async move {
                     match room_pool.find_or_create_room(room_id.clone()).await {
                         Ok(_) => {
                             match room_pool
-                                .add_player(socket.id, room_id.clone(), player.clone())
+                                .add_player(socket.id, room_id.clone(), player.clone(), false)
                                 .await
                             {
                                 Ok(player_in_room) => {
                                     let _ = socket.leave_all();
                                     let _ = socket.join(room_id.clone());
                                     let _ = socket.emit("join_room:success", room_id.clone());
-                                    let _ = socket
-                                        .within(room_id.clone())
-                                        .emit("message:join", player_in_room.minify());
+                                    room_pool.announce(
+                                        &room_id,
+                                        "message:join",
+                                        player_in_room.minify(),
+                                    );
 
-                                    let pool = room_pool.get().await;
-                                    let room = pool.get(&room_id).unwrap();
-                                    let _ = socket.within(room_id).emit("room_update", room);
+                                    room_pool
+                                        .check_auto_start(room_id.clone(), db.clone())
+                                        .await;
+                                    broadcast_room_update(&room_pool, room_id).await;
                                 }
                                 Err(reason) => {
                                     let _ = socket.emit("join_room:failure", reason);
@@ -497,7 +1027,7 @@ pub async fn handle_connection(
                 |socket: SocketRef,
                  Data::<String>(room_id): Data<String>,
                  room_pool: RoomPoolState| async move {
-                    match room_pool.get().await.get(&room_id) {
+                    match room_pool.public_room(&room_id).await {
                         Some(room) => {
                             let _ = socket.emit("room_update", room);
                         }
@@ -511,6 +1041,7 @@ pub async fn handle_connection(
             socket.on(
                 "set_team",
                 |socket: SocketRef,
+                 db: State<Arc<PrismaClient>>,
                  Data::<(String, Value)>((room_id, team)): Data<(String, Value)>,
                  room_pool: RoomPoolState| async move {
                     match room_pool
@@ -519,13 +1050,12 @@ pub async fn handle_connection(
                     {
                         Ok(cur_player) => {
                             let _ = socket.emit("set_team:success", ());
-                            let _ = socket
-                                .within(room_id.clone())
-                                .emit("message:team_modification", cur_player);
+                            room_pool.announce(&room_id, "message:team_modification", cur_player);
 
-                            let pool = room_pool.get().await;
-                            let room = pool.get(&room_id).unwrap();
-                            let _ = socket.within(room_id).emit("room_update", room);
+                            room_pool
+                                .check_auto_start(room_id.clone(), db.clone())
+                                .await;
+                            broadcast_room_update(&room_pool, room_id).await;
                         }
                         Err(reason) => {
                             let _ = socket.emit("set_team:failure", reason);
@@ -534,24 +1064,46 @@ pub async fn handle_connection(
                 },
             );
 
+            socket.on(
+                "set_color",
+                |socket: SocketRef,
+                 Data::<(String, Value)>((room_id, color)): Data<(String, Value)>,
+                 room_pool: RoomPoolState| async move {
+                    match room_pool
+                        .change_player_color(socket.id, room_id.clone(), color)
+                        .await
+                    {
+                        Ok(cur_player) => {
+                            let _ = socket.emit("set_color:success", ());
+                            room_pool.announce(&room_id, "message:color_modification", cur_player);
+
+                            broadcast_room_update(&room_pool, room_id).await;
+                        }
+                        Err(reason) => {
+                            let _ = socket.emit("set_color:failure", reason);
+                        }
+                    }
+                },
+            );
+
             socket.on(
                 "set_host",
                 |socket: SocketRef,
-                 Data::<(String, String)>((room_id, player_id)): Data<(String, String)>,
+                 Data::<(String, usize)>((room_id, color)): Data<(String, usize)>,
                  room_pool: State<RoomPoolStore>| async move {
                     match room_pool
-                        .change_player_host(socket.id, room_id.clone(), player_id)
+                        .change_player_host(socket.id, room_id.clone(), color)
                         .await
                     {
                         Ok((from, to)) => {
                             let _ = socket.emit("set_host:success", ());
-                            let _ = socket
-                                .within(room_id.clone())
-                                .emit("message:host_modification", json!((from, to)));
+                            room_pool.announce(
+                                &room_id,
+                                "message:host_modification",
+                                json!((from, to)),
+                            );
 
-                            let pool = room_pool.get().await;
-                            let room = pool.get(&room_id).unwrap();
-                            let _ = socket.within(room_id).emit("room_update", room);
+                            broadcast_room_update(&room_pool, room_id).await;
                         }
                         Err(reason) => {
                             let _ = socket.emit("set_host:failure", reason);
@@ -560,6 +1112,61 @@ pub async fn handle_connection(
                 },
             );
 
+            socket.on(
+                "add_bot",
+                |socket: SocketRef,
+                 Data::<(String, BotDifficulty)>((room_id, difficulty)): Data<(
+                    String,
+                    BotDifficulty,
+                )>,
+                 db: State<Arc<PrismaClient>>,
+                 room_pool: RoomPoolState| async move {
+                    match room_pool
+                        .add_bot(socket.id, room_id.clone(), difficulty)
+                        .await
+                    {
+                        Ok(bot) => {
+                            let _ = socket.emit("add_bot:success", ());
+                            room_pool.announce(&room_id, "message:join", bot);
+
+                            room_pool
+                                .check_auto_start(room_id.clone(), db.clone())
+                                .await;
+                            broadcast_room_update(&room_pool, room_id).await;
+                        }
+                        Err(reason) => {
+                            let _ = socket.emit("add_bot:failure", reason);
+                        }
+                    }
+                },
+            );
+
+            socket.on(
+                "remove_bot",
+                |socket: SocketRef,
+                 Data::<(String, String)>((room_id, username)): Data<(String, String)>,
+                 db: State<Arc<PrismaClient>>,
+                 room_pool: RoomPoolState| async move {
+                    match room_pool
+                        .remove_bot(socket.id, room_id.clone(), username)
+                        .await
+                    {
+                        Ok(bot) => {
+                            let _ = socket.emit("remove_bot:success", ());
+                            room_pool.announce(&room_id, "message:leave", bot);
+
+                            room_pool
+                                .check_auto_start(room_id.clone(), db.clone())
+                                .await;
+                            broadcast_room_update(&room_pool, room_id).await;
+                        }
+                        Err(reason) => {
+                            let _ = socket.emit("remove_bot:failure", reason);
+                        }
+                    }
+                },
+            );
+
             socket.on(
                 "modify_game_options",
                 |socket: SocketRef,
@@ -582,13 +1189,21 @@ pub async fn handle_connection(
                     {
                         Ok(player) => {
                             let _ = socket.emit("modify_game_options:success", ());
-                            let _ = socket
-                                .within(room_id.clone())
-                                .emit("message:options_modification", json!((player, prop, val)));
+                            // The seed stays on the server, like in room updates.
+                            let val = match prop.as_str() {
+                                "seed" => json!(!val.is_null()),
+                                _ => val,
+                            };
+                            room_pool.announce(
+                                &room_id,
+                                "message:options_modification",
+                                json!((player, prop, val)),
+                            );
 
-                            let pool = room_pool.get().await;
-                            let room = pool.get(&room_id).unwrap();
-                            let _ = socket.within(room_id).emit("room_update", room);
+                            room_pool
+                                .check_auto_start(room_id.clone(), db.clone())
+                                .await;
+                            broadcast_room_update(&room_pool, room_id).await;
                         }
                         Err(reason) => {
                             let _ = socket.emit("modify_game_options:failure", reason);
@@ -608,7 +1223,8 @@ pub async fn handle_connection(
                  room_pool: State<RoomPoolStore>| async move {
                     match room_pool.query_player(socket.id, room_id.clone()).await {
                         Ok(player) => {
-                            let _ = socket.within(room_id).emit("message", json!((player, msg)));
+                            room_pool.announce(&room_id, "message", json!((player, msg.clone())));
+                            room_pool.record_message(socket.id, room_id, msg).await;
                         }
                         Err(reason) => {
                             let _ = socket.emit("send_message:failure", reason);
@@ -620,6 +1236,7 @@ pub async fn handle_connection(
             socket.on(
                 "force_start",
                 |socket: SocketRef,
+                 db: State<Arc<PrismaClient>>,
                  Data::<String>(room_id): Data<String>,
                  room_pool: RoomPoolState| async move {
                     match room_pool
@@ -627,9 +1244,10 @@ pub async fn handle_connection(
                         .await
                     {
                         Ok(_) => {
-                            let pool = room_pool.get().await;
-                            let room = pool.get(&room_id).unwrap();
-                            let _ = socket.within(room_id).emit("room_update", room);
+                            room_pool
+                                .check_auto_start(room_id.clone(), db.clone())
+                                .await;
+                            broadcast_room_update(&room_pool, room_id).await;
                         }
                         Err(reason) => {
                             let _ = socket.emit("force_start:failure", reason);
@@ -638,9 +1256,173 @@ pub async fn handle_connection(
                 },
             );
 
+            socket.on(
+                "cancel_countdown",
+                |socket: SocketRef,
+                 Data::<String>(room_id): Data<String>,
+                 room_pool: RoomPoolState| async move {
+                    match room_pool.cancel_countdown(socket.id, room_id.clone()).await {
+                        Ok(_) => {
+                            room_pool.announce(&room_id, "countdown:cancel", ());
+                            broadcast_room_update(&room_pool, room_id).await;
+                        }
+                        Err(reason) => {
+                            let _ = socket.emit("cancel_countdown:failure", reason);
+                        }
+                    }
+                },
+            );
+
+            socket.on(
+                "attack",
+                |socket: SocketRef,
+                 Data::<(String, (i32, i32), (i32, i32), bool)>((room_id, from, to, half)): Data<(
+                    String,
+                    (i32, i32),
+                    (i32, i32),
+                    bool,
+                )>,
+                 room_pool: RoomPoolState| async move {
+                    match room_pool
+                        .queue_move(socket.id, room_id, Move { from, to, half })
+                        .await
+                    {
+                        Ok(queue) => {
+                            let _ = socket.emit("queue_update", queue);
+                        }
+                        Err(reason) => {
+                            let _ = socket.emit("attack:failure", reason);
+                        }
+                    }
+                },
+            );
+
+            socket.on(
+                "clear_queue",
+                |socket: SocketRef,
+                 Data::<String>(room_id): Data<String>,
+                 room_pool: RoomPoolState| async move {
+                    match room_pool.unqueue_moves(socket.id, room_id, true).await {
+                        Ok(queue) => {
+                            let _ = socket.emit("queue_update", queue);
+                        }
+                        Err(reason) => {
+                            let _ = socket.emit("clear_queue:failure", reason);
+                        }
+                    }
+                },
+            );
+
+            socket.on(
+                "pop_queue",
+                |socket: SocketRef,
+                 Data::<String>(room_id): Data<String>,
+                 room_pool: RoomPoolState| async move {
+                    match room_pool.unqueue_moves(socket.id, room_id, false).await {
+                        Ok(queue) => {
+                            let _ = socket.emit("queue_update", queue);
+                        }
+                        Err(reason) => {
+                            let _ = socket.emit("pop_queue:failure", reason);
+                        }
+                    }
+                },
+            );
+
+            socket.on(
+                "surrender",
+                |socket: SocketRef,
+                 Data::<String>(room_id): Data<String>,
+                 room_pool: RoomPoolState| async move {
+                    match room_pool.surrender(socket.id, room_id.clone()).await {
+                        Ok((player, spectating)) => {
+                            room_pool.announce(&room_id, "message:surrender", player);
+                            if !spectating {
+                                let _ = socket.disconnect();
+                            }
+                        }
+                        Err(reason) => {
+                            let _ = socket.emit("surrender:failure", reason);
+                        }
+                    }
+                },
+            );
+
+            socket.on(
+                "replay:play",
+                |socket: SocketRef,
+                 db: State<Arc<PrismaClient>>,
+                 Data::<String>(replay_id): Data<String>,
+                 playback: State<PlaybackStore>| async move {
+                    if playback.is_playing(socket.id, &replay_id).await {
+                        let _ = playback.command(socket.id, PlaybackCommand::Play).await;
+                        return;
+                    }
+                    match load_playback(&db, replay_id.clone()).await {
+                        Ok(replay) => {
+                            playback.start(socket.clone(), replay_id, replay).await;
+                            let _ = socket.emit("replay:play:success", ());
+                        }
+                        Err(reason) => {
+                            let _ = socket.emit("replay:play:failure", reason);
+                        }
+                    }
+                },
+            );
+
+            socket.on(
+                "replay:pause",
+                |socket: SocketRef, playback: State<PlaybackStore>| async move {
+                    if let Err(reason) = playback.command(socket.id, PlaybackCommand::Pause).await {
+                        let _ = socket.emit("replay:pause:failure", reason);
+                    }
+                },
+            );
+
+            socket.on(
+                "replay:speed",
+                |socket: SocketRef,
+                 Data::<i32>(speed): Data<i32>,
+                 playback: State<PlaybackStore>| async move {
+                    if !REPLAY_SPEED_OPTIONS.contains(&speed) {
+                        let _ = socket.emit("replay:speed:failure", "Invalid speed.");
+                        return;
+                    }
+                    if let Err(reason) = playback
+                        .command(socket.id, PlaybackCommand::Speed(speed))
+                        .await
+                    {
+                        let _ = socket.emit("replay:speed:failure", reason);
+                    }
+                },
+            );
+
+            socket.on(
+                "replay:seek",
+                |socket: SocketRef,
+                 Data::<u32>(turn): Data<u32>,
+                 playback: State<PlaybackStore>| async move {
+                    if let Err(reason) = playback
+                        .command(socket.id, PlaybackCommand::Seek(turn))
+                        .await
+                    {
+                        let _ = socket.emit("replay:seek:failure", reason);
+                    }
+                },
+            );
+
             socket.on_disconnect(
-                |socket: SocketRef, room_pool: State<RoomPoolStore>| async move {
-                    room_pool.remove_player(socket.id).await;
+                |socket: SocketRef,
+                 db: State<Arc<PrismaClient>>,
+                 room_pool: State<RoomPoolStore>,
+                 playback: State<PlaybackStore>| async move {
+                    playback.stop(socket.id).await;
+                    // The seat is only given up if the player didn't reconnect in time.
+                    let (db, room_pool) = ((*db).clone(), (*room_pool).clone());
+                    tokio::spawn(async move {
+                        tokio::time::sleep(Duration::from_secs(RECONNECT_GRACE_SECS)).await;
+                        room_pool.remove_player(socket.id, db).await;
+                    });
                 },
             )
         }
@@ -652,6 +1434,349 @@ pub async fn handle_connection(
     }
 }
 
+async fn broadcast_room_update(room_pool: &RoomPoolStore, room_id: String) {
+    if let Some(room) = room_pool.public_room(&room_id).await {
+        room_pool.announce(&room_id, "room_update", room);
+    }
+}
+
+// Starts the game when the countdown runs out, unless it was cancelled or the room can't
+// start anymore.
+async fn run_countdown(
+    room_pool: RoomPoolStore,
+    db: Arc<PrismaClient>,
+    room_id: String,
+    token: u32,
+) {
+    tokio::time::sleep(Duration::from_secs(START_COUNTDOWN_SECS)).await;
+
+    {
+        let mut binding = room_pool.pool.write().await;
+        let Some(room) = binding.get_mut(&room_id) else {
+            return;
+        };
+        if room.state != RoomState::Countdown || room.countdown_token != token {
+            return;
+        }
+        if !room.update_votes() {
+            let _ = room.set_state(RoomState::Lobby);
+            room_pool.announce(&room_id, "countdown:cancel", ());
+            room_pool.announce(&room_id, "room_state", room.state);
+            return;
+        }
+    }
+
+    match room_pool.start_game(room_id.clone(), db).await {
+        Ok(_) => {
+            room_pool.announce(&room_id, "game_started", ());
+        }
+        Err(reason) => {
+            // Back to the lobby, or the room could never count down again.
+            if let Some(room) = room_pool.pool.write().await.get_mut(&room_id) {
+                if room.set_state(RoomState::Lobby).is_ok() {
+                    room_pool.announce(&room_id, "room_state", room.state);
+                }
+            }
+            room_pool.announce(&room_id, "start_game:failure", reason);
+        }
+    }
+    broadcast_room_update(&room_pool, room_id).await;
+}
+
+// Drives a single room: one tick per turn until the game stops or the room disappears.
+async fn run_game_loop(
+    room_pool: RoomPoolStore,
+    db: Arc<PrismaClient>,
+    room_id: String,
+    period: Duration,
+) {
+    let Some(io) = room_pool.io.get() else {
+        return;
+    };
+    start_recording(&room_pool, &db, &room_id).await;
+    let mut interval = tokio::time::interval(period);
+
+    loop {
+        interval.tick().await;
+
+        let mut dropped = Vec::new();
+        {
+            let mut binding = room_pool.pool.write().await;
+            let room = match binding.get_mut(&room_id) {
+                Some(room) if room.state == RoomState::Playing => room,
+                _ => break,
+            };
+
+            let mut finished = false;
+            let events = room.tick();
+            room.record_turn();
+            for event in events {
+                match event {
+                    TurnEvent::Captured { captor, victim } => {
+                        room_pool.announce(
+                            &room_id,
+                            "message:captured",
+                            json!((room.players[captor].minify(), room.players[victim].minify())),
+                        );
+                        if !room.game_options.death_spectating {
+                            dropped.extend(session_socket(io, &room.players[victim].session));
+                        }
+                    }
+                    TurnEvent::AfkWarning { player, turns_left } => {
+                        if let Some(socket) = session_socket(io, &room.players[player].session) {
+                            let _ = socket.emit("afk_warning", turns_left);
+                        }
+                    }
+                    TurnEvent::AfkSurrendered(player) => {
+                        room_pool.announce(
+                            &room_id,
+                            "message:surrender",
+                            room.players[player].minify(),
+                        );
+                        if !room.game_options.death_spectating {
+                            dropped.extend(session_socket(io, &room.players[player].session));
+                        }
+                    }
+                    TurnEvent::GameOver(result) => {
+                        info!("Game in room {} is over.", room_id);
+                        finished = true;
+                        room_pool.announce(&room_id, "game_over", result);
+                        room_pool.announce(&room_id, "room_state", room.state);
+                    }
+                }
+            }
+
+            if finished || room.turn.is_multiple_of(REPLAY_FLUSH_INTERVAL) {
+                let participants = if finished {
+                    room.players
+                        .iter()
+                        .filter(|x| !x.is_spectating())
+                        .map(|x| x.player_id.clone())
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+                if let Some(recorder) = room.recorder.as_mut() {
+                    let records = recorder.drain();
+                    tokio::spawn(save_replay(
+                        db.clone(),
+                        recorder.replay_id.clone(),
+                        records,
+                        participants,
+                    ));
+                }
+            }
+
+            room_pool.announce(&room_id, "leaderboard", room.leaderboard());
+
+            // Every player gets their own fogged view, the room channel never sees the map.
+            for (socket_id, update) in room.game_updates() {
+                if let Some(socket) = io.get_socket(socket_id) {
+                    let _ = socket.emit("game_update", update);
+                }
+            }
+            if let Some(bots) = io.of(BOT_NAMESPACE) {
+                for (socket_id, frame) in room.bot_frames() {
+                    let _ = bots
+                        .clone()
+                        .within(socket_id.to_string())
+                        .emit("frame", frame);
+                }
+            }
+
+            if finished {
+                room.recorder = None;
+                // Players who left during the game were kept until now.
+                room.players
+                    .retain(|x| x.bot.is_some() || session_socket(io, &x.session).is_some());
+                room.ensure_host();
+                if room.reset().is_ok() {
+                    room_pool.announce(&room_id, "room_state", room.state);
+                }
+                room_pool.announce(&room_id, "room_update", room.to_public());
+            }
+        }
+
+        // Dead players are only kicked once the room lock is released.
+        for socket in dropped {
+            let _ = socket.disconnect();
+        }
+    }
+
+    info!("Game loop of room {} stopped.", room_id);
+}
+
+// The socket a seat is bound to, if it is still connected. Bots connect through their own
+// namespace, which io.get_socket doesn't look into.
+fn session_socket(io: &SocketIo, session: &Session) -> Option<SocketRef> {
+    if !session.compact {
+        return io.get_socket(session.socket_id);
+    }
+    // Each bot socket sits in a room named after its own id.
+    io.of(BOT_NAMESPACE)?
+        .within(session.socket_id.to_string())
+        .sockets()
+        .ok()?
+        .into_iter()
+        .next()
+}
+
+// Creates the Replay row of a game that just started. The game is still played, just not
+// recorded, if this fails.
+async fn start_recording(room_pool: &RoomPoolStore, db: &PrismaClient, room_id: &str) {
+    let (width, height, seed) = match room_pool.pool.read().await.get(room_id) {
+        Some(room) => (
+            room.map.len(),
+            room.map.first().map_or(0, |x| x.len()),
+            room.seed,
+        ),
+        None => return,
+    };
+
+    match db
+        .replay()
+        .create(
+            width as i32,
+            height as i32,
+            vec![stored_replay::seed::set(seed.to_string())],
+        )
+        .exec()
+        .await
+    {
+        Ok(replay) => {
+            if let Some(room) = room_pool.pool.write().await.get_mut(room_id) {
+                let mut recorder = ReplayRecorder::new(replay.id);
+                recorder.record_turn(room.turn, &room.map);
+                room.recorder = Some(recorder);
+            }
+        }
+        Err(err) => warn!("Failed to create replay for room {}: {}", room_id, err),
+    }
+}
+
+// Writes recorded turns, keyframes and chat. Once the game is over `participants` is
+// filled, and the replay is added to each of their Player.replayIds.
+async fn save_replay(
+    db: Arc<PrismaClient>,
+    replay_id: String,
+    records: ReplayRecords,
+    participants: Vec<String>,
+) {
+    if !records.diffs.is_empty() {
+        let diffs = records
+            .diffs
+            .into_iter()
+            .map(|(turn, data)| map_diff::create_unchecked(turn, data, replay_id.clone(), vec![]))
+            .collect();
+        if let Err(err) = db.map_diff().create_many(diffs).exec().await {
+            warn!("Failed to save map diffs of replay {}: {}", replay_id, err);
+        }
+    }
+
+    if !records.keyframes.is_empty() {
+        let keyframes = records
+            .keyframes
+            .into_iter()
+            .map(|(turn, data)| {
+                map_keyframe::create_unchecked(turn, data, replay_id.clone(), vec![])
+            })
+            .collect();
+        if let Err(err) = db.map_keyframe().create_many(keyframes).exec().await {
+            warn!("Failed to save keyframes of replay {}: {}", replay_id, err);
+        }
+    }
+
+    if !records.messages.is_empty() {
+        let messages = records
+            .messages
+            .into_iter()
+            .map(|x| {
+                message::create_unchecked(x.turn, x.content, x.sender_id, replay_id.clone(), vec![])
+            })
+            .collect();
+        if let Err(err) = db.message().create_many(messages).exec().await {
+            warn!("Failed to save messages of replay {}: {}", replay_id, err);
+        }
+    }
+
+    if !participants.is_empty() {
+        if let Err(err) = db
+            .player()
+            .update_many(
+                vec![player::id::in_vec(participants)],
+                vec![player::replay_ids::push(vec![replay_id.clone()])],
+            )
+            .exec()
+            .await
+        {
+            warn!(
+                "Failed to link replay {} to its players: {}",
+                replay_id, err
+            );
+        }
+    }
+}
+
+// Reads a stored replay with its diffs and chat, ready to be streamed.
+async fn load_playback(db: &PrismaClient, replay_id: String) -> Result<Playback, &'static str> {
+    let replay = match db
+        .replay()
+        .find_unique(stored_replay::id::equals(replay_id))
+        .with(
+            stored_replay::game_records::fetch(vec![])
+                .order_by(map_diff::turn::order(Direction::Asc)),
+        )
+        .with(
+            stored_replay::keyframes::fetch(vec![])
+                .order_by(map_keyframe::turn::order(Direction::Asc)),
+        )
+        .with(
+            stored_replay::message_records::fetch(vec![])
+                .order_by(message::turn::order(Direction::Asc))
+                .with(message::sender::fetch()),
+        )
+        .exec()
+        .await
+    {
+        Ok(Some(replay)) => replay,
+        _ => return Err("Replay not found."),
+    };
+
+    let diffs = parse_records(
+        replay
+            .game_records
+            .unwrap_or_default()
+            .into_iter()
+            .map(|x| (x.turn, x.data))
+            .collect(),
+    )?;
+    let keyframes = parse_records(
+        replay
+            .keyframes
+            .unwrap_or_default()
+            .into_iter()
+            .map(|x| (x.turn, x.data))
+            .collect(),
+    )?;
+    let messages = replay
+        .message_records
+        .unwrap_or_default()
+        .into_iter()
+        .map(|x| {
+            let username = x.sender.map(|x| x.username).unwrap_or_default();
+            (x.turn as u32, username, x.content)
+        })
+        .collect();
+
+    Ok(Playback::new(
+        replay.map_width as usize,
+        replay.map_height as usize,
+        diffs,
+        keyframes,
+        messages,
+    ))
+}
+
 async fn get_player(
     db: State<Arc<PrismaClient>>,
     username: String,
@@ -1,6 +1,6 @@
 use serde::Serialize;
 
-#[derive(PartialEq, Serialize, Clone, Copy)]
+#[derive(PartialEq, Serialize, Clone, Copy, Debug)]
 pub enum TileType {
     King = 0,     // base
     City = 1,     // spawner
@@ -11,35 +11,67 @@ pub enum TileType {
     Swamp = 6,
 }
 
-#[derive(Serialize, Clone, Copy, PartialEq)]
+impl TryFrom<u8> for TileType {
+    type Error = &'static str;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(TileType::King),
+            1 => Ok(TileType::City),
+            2 => Ok(TileType::Fog),
+            3 => Ok(TileType::Obstacle),
+            4 => Ok(TileType::Plain),
+            5 => Ok(TileType::Mountain),
+            6 => Ok(TileType::Swamp),
+            _ => Err("Invalid tile type."),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
 pub struct Block {
-    x: i32,
-    y: i32,
-    tile_type: TileType,
-    unit: i64,
-    color: i16,
-    team: i16,
-    is_always_revealed: bool,
+    pub x: i32,
+    pub y: i32,
+    pub tile_type: TileType,
+    pub unit: i64,
+    pub color: i16,
+    pub team: i16,
+    pub is_always_revealed: bool,
 }
 
 impl Block {
-    pub fn init_king(mut self, color: i16) {
+    pub fn new(x: i32, y: i32, tile_type: TileType, unit: i64) -> Self {
+        Block {
+            x,
+            y,
+            tile_type,
+            unit,
+            color: 0,
+            team: 0,
+            is_always_revealed: false,
+        }
+    }
+
+    pub fn init_king(&mut self, color: i16) {
         self.color = color;
         self.tile_type = TileType::King;
         self.unit = 1;
     }
 
-    pub fn dominated_by(mut self, color: i16, team: i16) {
+    pub fn dominated_by(&mut self, color: i16, team: i16) {
         self.color = color;
         self.team = team;
     }
 
-    pub fn entered_by(mut self, color: i16, team: i16, unit: i64) {
-        if self.team == team {
+    // Neutral tiles have team 0 too, so they are never allied.
+    pub fn is_allied(&self, team: i16) -> bool {
+        self.color != 0 && self.team == team
+    }
+
+    pub fn entered_by(&mut self, color: i16, team: i16, unit: i64) {
+        // Allied land is only reinforced, it stays with its owner.
+        if self.is_allied(team) {
             self.unit += unit;
-            if self.tile_type != TileType::King {
-                self.dominated_by(color, team);
-            }
         } else {
             if self.unit >= unit {
                 self.unit -= unit;
@@ -0,0 +1,159 @@
+use std::collections::VecDeque;
+
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    block::{Block, TileType},
+    command::Move,
+    map_generator::{neighbours, Map},
+};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BotDifficulty {
+    // Wanders around, grabbing whatever is next to a random army.
+    Easy,
+    // Sends its biggest army to the best target in sight.
+    Normal,
+    // Same as Normal, but acts every turn and keeps half of its king's army home.
+    Hard,
+}
+
+impl BotDifficulty {
+    fn acts_on(self, turn: u32) -> bool {
+        match self {
+            BotDifficulty::Easy => turn.is_multiple_of(3),
+            BotDifficulty::Normal => turn.is_multiple_of(2),
+            BotDifficulty::Hard => true,
+        }
+    }
+}
+
+// Picks the next move of a bot from its fogged `view`. None when it waits this turn.
+pub fn next_move(
+    view: &Map,
+    color: i16,
+    team: i16,
+    difficulty: BotDifficulty,
+    turn: u32,
+    rng: &mut impl Rng,
+) -> Option<Move> {
+    if !difficulty.acts_on(turn) {
+        return None;
+    }
+    let armies: Vec<&Block> = view
+        .iter()
+        .flatten()
+        .filter(|x| x.color == color && x.unit > 1)
+        .collect();
+
+    let (from, to) = match difficulty {
+        BotDifficulty::Easy => {
+            let source = armies.choose(rng)?;
+            let from = (source.x as usize, source.y as usize);
+            (from, greedy_step(view, from, team, rng)?)
+        }
+        _ => {
+            let source = armies.iter().max_by_key(|x| x.unit)?;
+            let from = (source.x as usize, source.y as usize);
+            let to = match planned_step(view, from, team) {
+                Some(to) => to,
+                None => greedy_step(view, from, team, rng)?,
+            };
+            (from, to)
+        }
+    };
+
+    Some(Move {
+        from: (from.0 as i32, from.1 as i32),
+        to: (to.0 as i32, to.1 as i32),
+        half: difficulty == BotDifficulty::Hard && view[from.0][from.1].tile_type == TileType::King,
+    })
+}
+
+// How much a tile is worth taking. None for tiles that can't or shouldn't be attacked.
+fn target_score(block: &Block, team: i16) -> Option<i64> {
+    if block.is_allied(team) {
+        return None;
+    }
+    let owned = block.color != 0;
+    match block.tile_type {
+        TileType::Mountain | TileType::Obstacle => None,
+        TileType::King => Some(1000),
+        TileType::City if owned => Some(200),
+        TileType::City => Some(60),
+        TileType::Plain if owned => Some(100),
+        TileType::Plain => Some(20),
+        TileType::Swamp if owned => Some(50),
+        TileType::Swamp => Some(1),
+        TileType::Fog => Some(5),
+    }
+}
+
+fn passable(block: &Block) -> bool {
+    block.tile_type != TileType::Mountain && block.tile_type != TileType::Obstacle
+}
+
+// Unknown tiles are assumed to be empty.
+fn can_take(army: i64, block: &Block) -> bool {
+    block.tile_type == TileType::Fog || army - 1 > block.unit
+}
+
+// The best neighbour the army can take, or any passable one to keep spreading out.
+fn greedy_step(
+    view: &Map,
+    (x, y): (usize, usize),
+    team: i16,
+    rng: &mut impl Rng,
+) -> Option<(usize, usize)> {
+    let army = view[x][y].unit;
+    let mut steps: Vec<(usize, usize)> = neighbours(view, x, y)
+        .filter(|&(nx, ny)| passable(&view[nx][ny]))
+        .collect();
+    steps.shuffle(rng);
+
+    steps
+        .iter()
+        .filter(|&&(nx, ny)| can_take(army, &view[nx][ny]))
+        .filter_map(|&(nx, ny)| Some((target_score(&view[nx][ny], team)?, (nx, ny))))
+        .max_by_key(|x| x.0)
+        .map(|x| x.1)
+        .or_else(|| steps.first().copied())
+}
+
+// First step on the shortest path towards the target with the best score for its distance.
+fn planned_step(view: &Map, (x, y): (usize, usize), team: i16) -> Option<(usize, usize)> {
+    let army = view[x][y].unit;
+    let mut parents = vec![vec![None; view[0].len()]; view.len()];
+    let mut queue = VecDeque::from([((x, y), 0)]);
+    parents[x][y] = Some((x, y));
+    let mut best: Option<(i64, (usize, usize))> = None;
+
+    while let Some(((cx, cy), distance)) = queue.pop_front() {
+        if (cx, cy) != (x, y) && can_take(army, &view[cx][cy]) {
+            if let Some(score) = target_score(&view[cx][cy], team) {
+                let value = score * 100 / (distance + 1);
+                if best.is_none_or(|(best, _)| value > best) {
+                    best = Some((value, (cx, cy)));
+                }
+            }
+        }
+        for (nx, ny) in neighbours(view, cx, cy) {
+            if parents[nx][ny].is_none() && passable(&view[nx][ny]) {
+                parents[nx][ny] = Some((cx, cy));
+                queue.push_back(((nx, ny), distance + 1));
+            }
+        }
+    }
+
+    // Walk back from the target until the tile next to the army.
+    let (_, mut step) = best?;
+    while let Some(parent) = parents[step.0][step.1] {
+        if parent == (x, y) {
+            return Some(step);
+        }
+        step = parent;
+    }
+    None
+}
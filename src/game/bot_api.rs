@@ -0,0 +1,167 @@
+use std::{sync::Arc, time::Duration};
+
+use querystring::querify;
+use socketioxide::extract::{Data, SocketRef, State};
+use tracing::info;
+
+use super::{
+    broadcast_room_update, command::Move, constants::RECONNECT_GRACE_SECS, get_query_param,
+    RoomPoolState, RoomPoolStore,
+};
+use crate::prisma::{player, PrismaClient};
+
+pub const BOT_NAMESPACE: &str = "/bot";
+
+// A move as sent by bots: (from_x, from_y, to_x, to_y, half).
+type CompactMove = (i32, i32, i32, i32, bool);
+
+// Namespace for external bots. They log in with the api_key of their Player, get a
+// "frame" with their whole fogged view as flat arrays every turn, and send moves in batches.
+pub async fn handle_bot_connection(
+    socket: SocketRef,
+    db: State<Arc<PrismaClient>>,
+    room_pool: State<RoomPoolStore>,
+) {
+    let queries = querify(socket.req_parts().uri.query().unwrap_or_default());
+    let api_key = get_query_param(queries, "api_key");
+    if api_key.is_empty() {
+        let _ = socket.emit("login:failure", "Invalid api key.");
+        let _ = socket.disconnect();
+        return;
+    }
+
+    let player = match db
+        .player()
+        .find_unique(player::api_key::equals(Some(api_key)))
+        .exec()
+        .await
+    {
+        Ok(Some(player)) => player,
+        _ => {
+            let _ = socket.emit("login:failure", "Invalid api key.");
+            let _ = socket.disconnect();
+            return;
+        }
+    };
+    info!(
+        "Bot {} ({}) successfully logged in.",
+        player.username, player.id
+    );
+    let _ = socket.emit("login:success", ());
+
+    // A bot that dropped out of a room takes its seat back.
+    if let Some(resync) = room_pool
+        .reconnect_player(socket.id, &player.id, true)
+        .await
+    {
+        info!("Bot {} reconnected.", player.username);
+        let _ = socket.join(socket.id.to_string());
+        let _ = socket.join(resync.room_id.clone());
+        let _ = socket.emit("resync", resync);
+    }
+
+    socket.on(
+        "join_room",
+        |socket: SocketRef,
+         Data::<String>(room_id): Data<String>,
+         db: State<Arc<PrismaClient>>,
+         room_pool: RoomPoolState| async move {
+            if let Err(reason) = room_pool.find_or_create_room(room_id.clone()).await {
+                let _ = socket.emit("join_room:failure", reason);
+                return;
+            }
+            match room_pool
+                .add_player(socket.id, room_id.clone(), player.clone(), true)
+                .await
+            {
+                Ok(player_in_room) => {
+                    let _ = socket.leave_all();
+                    let _ = socket.join(socket.id.to_string());
+                    let _ = socket.join(room_id.clone());
+                    let _ = socket.emit("join_room:success", room_id.clone());
+                    room_pool.announce(&room_id, "message:join", player_in_room.minify());
+                    room_pool
+                        .check_auto_start(room_id.clone(), db.clone())
+                        .await;
+                    broadcast_room_update(&room_pool, room_id).await;
+                }
+                Err(reason) => {
+                    let _ = socket.emit("join_room:failure", reason);
+                }
+            }
+        },
+    );
+
+    socket.on(
+        "force_start",
+        |socket: SocketRef,
+         db: State<Arc<PrismaClient>>,
+         Data::<String>(room_id): Data<String>,
+         room_pool: RoomPoolState| async move {
+            match room_pool
+                .player_force_start(socket.id, room_id.clone())
+                .await
+            {
+                Ok(_) => {
+                    room_pool
+                        .check_auto_start(room_id.clone(), db.clone())
+                        .await;
+                    broadcast_room_update(&room_pool, room_id).await;
+                }
+                Err(reason) => {
+                    let _ = socket.emit("force_start:failure", reason);
+                }
+            }
+        },
+    );
+
+    // Queues every valid move of the batch. Rejected ones are reported by their index.
+    socket.on(
+        "moves",
+        |socket: SocketRef,
+         Data::<(String, Vec<CompactMove>)>((room_id, moves)): Data<(String, Vec<CompactMove>)>,
+         room_pool: RoomPoolState| async move {
+            let mut queue_len = 0;
+            let mut failures = Vec::new();
+            for (i, (from_x, from_y, to_x, to_y, half)) in moves.into_iter().enumerate() {
+                let next = Move {
+                    from: (from_x, from_y),
+                    to: (to_x, to_y),
+                    half,
+                };
+                match room_pool.queue_move(socket.id, room_id.clone(), next).await {
+                    Ok(queue) => queue_len = queue.len(),
+                    Err(reason) => failures.push((i, reason)),
+                }
+            }
+
+            if failures.is_empty() {
+                let _ = socket.emit("moves:success", queue_len);
+            } else {
+                let _ = socket.emit("moves:failure", failures);
+            }
+        },
+    );
+
+    socket.on(
+        "surrender",
+        |socket: SocketRef, Data::<String>(room_id): Data<String>, room_pool: RoomPoolState| async move {
+            match room_pool.surrender(socket.id, room_id.clone()).await {
+                Ok((player, _)) => room_pool.announce(&room_id, "message:surrender", player),
+                Err(reason) => {
+                    let _ = socket.emit("surrender:failure", reason);
+                }
+            }
+        },
+    );
+
+    socket.on_disconnect(
+        |socket: SocketRef, db: State<Arc<PrismaClient>>, room_pool: State<RoomPoolStore>| async move {
+            let (db, room_pool) = ((*db).clone(), (*room_pool).clone());
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(RECONNECT_GRACE_SECS)).await;
+                room_pool.remove_player(socket.id, db).await;
+            });
+        },
+    );
+}
@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+
+use super::{block::TileType, map_generator::Map};
+
+// A queued army movement. Coordinates index the map as map[x][y].
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Move {
+    pub from: (i32, i32),
+    pub to: (i32, i32),
+    pub half: bool,
+}
+
+// Everything a player can do during a game.
+pub enum Command {
+    Move(Move),
+    ClearQueue,
+    // Drops the last queued move.
+    PopQueue,
+    Surrender,
+}
+
+pub enum MoveResult {
+    // The move can't be made anymore, e.g. the source tile was lost.
+    Invalid,
+    Moved,
+    // The move took the king of the player with this color.
+    CapturedKing(i16),
+}
+
+impl Move {
+    // Checks what can be known when the move is queued. Ownership of `from` is only
+    // checked when the move is executed, so players can queue a path ahead of their army.
+    pub fn validate(&self, map: &Map) -> Result<(), &'static str> {
+        let in_bounds = |(x, y): (i32, i32)| {
+            x >= 0 && y >= 0 && (x as usize) < map.len() && (y as usize) < map[x as usize].len()
+        };
+        if !in_bounds(self.from) || !in_bounds(self.to) {
+            return Err("Move out of map bounds.");
+        }
+        if (self.from.0 - self.to.0).abs() + (self.from.1 - self.to.1).abs() != 1 {
+            return Err("Tiles are not adjacent.");
+        }
+        if map[self.to.0 as usize][self.to.1 as usize].tile_type == TileType::Mountain {
+            return Err("Cannot move onto a mountain.");
+        }
+        Ok(())
+    }
+
+    pub fn execute(&self, map: &mut Map, color: i16, team: i16) -> MoveResult {
+        if self.validate(map).is_err() {
+            return MoveResult::Invalid;
+        }
+        let (fx, fy) = (self.from.0 as usize, self.from.1 as usize);
+        let (tx, ty) = (self.to.0 as usize, self.to.1 as usize);
+        // Armies passing through allied land can be moved on, the tile stays with its owner.
+        // Allied kings and cities are left alone so teammates can't strip their defences.
+        let from = map[fx][fy];
+        let passable = matches!(from.tile_type, TileType::Plain | TileType::Swamp);
+        if from.color != color && !(from.is_allied(team) && passable) {
+            return MoveResult::Invalid;
+        }
+
+        let movable = map[fx][fy].get_movable_unit();
+        let unit = if self.half {
+            (movable + 1) / 2
+        } else {
+            movable
+        };
+        if unit == 0 {
+            return MoveResult::Invalid;
+        }
+
+        let target = map[tx][ty];
+        map[fx][fy].unit -= unit;
+        map[tx][ty].entered_by(color, team, unit);
+
+        if target.tile_type == TileType::King && target.color != color && map[tx][ty].color == color
+        {
+            return MoveResult::CapturedKing(target.color);
+        }
+        MoveResult::Moved
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::map_generator::blank_map;
+
+    fn step(from: (i32, i32), to: (i32, i32)) -> Move {
+        Move {
+            from,
+            to,
+            half: false,
+        }
+    }
+
+    #[test]
+    fn armies_pass_through_allied_land() {
+        let mut map = blank_map(3, 1);
+        map[0][0].dominated_by(1, 1);
+        map[0][0].unit = 10;
+        map[1][0].dominated_by(2, 1);
+        map[1][0].unit = 3;
+
+        assert!(matches!(
+            step((0, 0), (1, 0)).execute(&mut map, 1, 1),
+            MoveResult::Moved
+        ));
+        assert_eq!((map[1][0].unit, map[1][0].color), (12, 2));
+
+        // Player 1 moves on from its ally's tile, which keeps its owner.
+        assert!(matches!(
+            step((1, 0), (2, 0)).execute(&mut map, 1, 1),
+            MoveResult::Moved
+        ));
+        assert_eq!((map[1][0].unit, map[1][0].color), (1, 2));
+        assert_eq!((map[2][0].unit, map[2][0].color), (11, 1));
+    }
+
+    #[test]
+    fn allied_kings_and_cities_keep_their_army() {
+        let mut map = blank_map(3, 1);
+        map[0][0].init_king(2);
+        map[0][0].dominated_by(2, 1);
+        map[0][0].unit = 20;
+        map[2][0].tile_type = TileType::City;
+        map[2][0].dominated_by(2, 1);
+        map[2][0].unit = 20;
+
+        for (from, to) in [((0, 0), (1, 0)), ((2, 0), (1, 0))] {
+            assert!(matches!(
+                step(from, to).execute(&mut map, 1, 1),
+                MoveResult::Invalid
+            ));
+        }
+        assert_eq!(
+            (map[0][0].unit, map[2][0].unit, map[1][0].unit),
+            (20, 20, 0)
+        );
+    }
+
+    #[test]
+    fn enemy_and_neutral_land_is_not_allied() {
+        let mut map = blank_map(3, 1);
+        map[0][0].dominated_by(2, 2);
+        map[0][0].unit = 10;
+        map[1][0].unit = 4;
+
+        assert!(matches!(
+            step((0, 0), (1, 0)).execute(&mut map, 1, 1),
+            MoveResult::Invalid
+        ));
+
+        // Neutral land shares team 0 with nobody, it is always fought over.
+        assert!(!map[1][0].is_allied(0));
+        map[0][0].dominated_by(1, 1);
+        assert!(matches!(
+            step((0, 0), (1, 0)).execute(&mut map, 1, 1),
+            MoveResult::Moved
+        ));
+        assert_eq!((map[1][0].unit, map[1][0].color), (5, 1));
+    }
+}
@@ -33,9 +33,40 @@ pub const WARRING_STATES: [&str; 13] = [
 
 pub const FORCE_START_OK: [i32; 13] = [1, 2, 2, 3, 3, 4, 5, 5, 6, 6, 7, 7, 8];
 //                    0  1  2  3  4  5  6  7  8  9 10 11 12
+// Seconds between enough votes and the game start, the host may cancel meanwhile
+pub const START_COUNTDOWN_SECS: u64 = 5;
 pub const MAX_TEAM_NUM: usize = 12;
 pub const SPEED_OPTIONS: [f32; 5] = [0.5, 1.0, 2.0, 3.0, 4.0];
 pub const REPLAY_SPEED_OPTIONS: [i32; 4] = [1, 2, 4, 8];
+// Duration of one turn at game_speed 1.0, in milliseconds
+pub const TURN_INTERVAL_MS: f32 = 500.0;
+
+// Map generation, the room's 0..1 sliders are scaled into these ranges
+pub const MIN_MAP_SIZE: usize = 10;
+pub const MAX_MAP_SIZE: usize = 40;
+pub const MAX_MOUNTAIN_RATIO: f32 = 0.3;
+pub const MAX_CITY_RATIO: f32 = 0.08;
+pub const MAX_SWAMP_RATIO: f32 = 0.15;
+pub const CITY_MIN_UNIT: i64 = 40;
+pub const CITY_MAX_UNIT: i64 = 50;
+pub const KING_PLACEMENT_ATTEMPTS: usize = 100;
+
+// Economy, all owned land gains one unit every LAND_GROWTH_INTERVAL turns
+pub const LAND_GROWTH_INTERVAL: u32 = 25;
+
+// Recorded replay data is written to the database every REPLAY_FLUSH_INTERVAL turns
+pub const REPLAY_FLUSH_INTERVAL: u32 = 20;
+// A full map snapshot is stored every REPLAY_KEYFRAME_INTERVAL turns for seeking
+pub const REPLAY_KEYFRAME_INTERVAL: u32 = 50;
+
+// Idle players are warned AFK_WARNING_TURNS turns before being surrendered
+pub const DEFAULT_AFK_TURNS: u32 = 120;
+pub const AFK_WARNING_TURNS: u32 = 20;
+
+// Disconnected players keep their seat this long so they can reconnect
+pub const RECONNECT_GRACE_SECS: u64 = 30;
+// Chat messages kept in memory to resync reconnecting players
+pub const CHAT_BACKLOG_SIZE: usize = 100;
 
 pub const DEFAULT_BGCOLO: &str = "#495468";
 pub const NOT_REVEALED_FILL: &str = "#3b414f";
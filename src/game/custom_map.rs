@@ -0,0 +1,162 @@
+use std::cmp::Reverse;
+
+use rand::{seq::SliceRandom, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use super::{
+    block::{Block, TileType},
+    map_generator::Map,
+    player_in_room::PlayerInRoom,
+};
+
+// One entry of CustomMapData.mapTilesData, as saved by the map editor:
+// [tile_type, team, unit, is_always_revealed, priority]
+type CustomMapTileData = (u8, i16, i64, bool, i32);
+
+// Parses the stored tile JSON (indexed as tiles[x][y]) into a playable map.
+// King tiles are spawn points; the ones left without a player become plain land.
+pub fn load_custom_map<S>(
+    tiles_data: &str,
+    width: usize,
+    height: usize,
+    players: &[PlayerInRoom<S>],
+    seed: u64,
+) -> Result<Map, &'static str> {
+    let tiles: Vec<Vec<CustomMapTileData>> =
+        serde_json::from_str(tiles_data).map_err(|_| "Invalid custom map data.")?;
+
+    if width == 0 || height == 0 || tiles.len() != width || tiles.iter().any(|x| x.len() != height)
+    {
+        return Err("Custom map size doesn't match its data.");
+    }
+
+    let mut map: Map = Vec::with_capacity(width);
+    let mut spawns: Vec<(usize, usize, i32)> = Vec::new();
+    for (x, column) in tiles.into_iter().enumerate() {
+        let mut blocks = Vec::with_capacity(height);
+        for (y, (tile_type, _, unit, is_always_revealed, priority)) in
+            column.into_iter().enumerate()
+        {
+            let tile_type = TileType::try_from(tile_type)?;
+            if tile_type == TileType::Fog || tile_type == TileType::Obstacle || unit < 0 {
+                return Err("Invalid custom map data.");
+            }
+            if tile_type == TileType::King {
+                spawns.push((x, y, priority));
+            }
+
+            let mut block = Block::new(x as i32, y as i32, tile_type, unit);
+            block.is_always_revealed = is_always_revealed;
+            blocks.push(block);
+        }
+        map.push(blocks);
+    }
+
+    let mut fighters: Vec<&PlayerInRoom<S>> =
+        players.iter().filter(|x| !x.is_spectating()).collect();
+    if fighters.is_empty() {
+        return Err("No players to start the game.");
+    }
+    if fighters.len() > spawns.len() {
+        return Err("Not enough spawn points on custom map.");
+    }
+
+    // Higher priority spawns are handed out first. Both the players and spawns sharing a
+    // priority are shuffled so join order gives no advantage.
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    fighters.shuffle(&mut rng);
+    spawns.shuffle(&mut rng);
+    spawns.sort_by_key(|x| Reverse(x.2));
+
+    for (i, &(x, y, _)) in spawns.iter().enumerate() {
+        match fighters.get(i) {
+            Some(player) => {
+                map[x][y].init_king(player.color as i16);
+                map[x][y].dominated_by(player.color as i16, player.team as i16);
+            }
+            None => {
+                map[x][y].tile_type = TileType::Plain;
+                map[x][y].unit = 0;
+            }
+        }
+    }
+
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 3x1 map: a spawn of priority 0, a spawn of priority 5 and a city.
+    const TILES: &str = "[[[0, 0, 1, false, 0]], [[0, 0, 1, false, 5]], [[1, 0, 40, true, 0]]]";
+
+    fn players(count: usize) -> Vec<PlayerInRoom> {
+        (1..=count)
+            .map(|i| PlayerInRoom {
+                color: i,
+                team: i,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tiles_are_loaded_as_saved() {
+        let map = load_custom_map(TILES, 3, 1, &players(2), 1).unwrap();
+        assert!(map[2][0].tile_type == TileType::City);
+        assert_eq!((map[2][0].unit, map[2][0].color), (40, 0));
+        assert!(map[2][0].is_always_revealed);
+    }
+
+    #[test]
+    fn malformed_or_resized_data_is_rejected() {
+        let fighters = players(1);
+        assert_eq!(
+            load_custom_map("not json", 3, 1, &fighters, 1),
+            Err("Invalid custom map data.")
+        );
+        assert_eq!(
+            load_custom_map(TILES, 2, 1, &fighters, 1),
+            Err("Custom map size doesn't match its data.")
+        );
+        assert_eq!(
+            load_custom_map(TILES, 3, 2, &fighters, 1),
+            Err("Custom map size doesn't match its data.")
+        );
+        // Fog only exists in views, never on a saved map.
+        assert_eq!(
+            load_custom_map("[[[2, 0, 0, false, 0]]]", 1, 1, &fighters, 1),
+            Err("Invalid custom map data.")
+        );
+        assert_eq!(
+            load_custom_map("[[[4, 0, -1, false, 0]]]", 1, 1, &fighters, 1),
+            Err("Invalid custom map data.")
+        );
+    }
+
+    #[test]
+    fn maps_need_a_spawn_per_player() {
+        assert_eq!(
+            load_custom_map(TILES, 3, 1, &players(3), 1),
+            Err("Not enough spawn points on custom map.")
+        );
+
+        // Spectators don't need a spawn.
+        let mut players = players(3);
+        players[2].set_spectate();
+        assert!(load_custom_map(TILES, 3, 1, &players, 1).is_ok());
+    }
+
+    #[test]
+    fn spawns_are_handed_out_by_priority() {
+        for seed in 0..8 {
+            let map = load_custom_map(TILES, 3, 1, &players(1), seed).unwrap();
+            assert!(map[1][0].tile_type == TileType::King);
+            assert_eq!((map[1][0].color, map[1][0].team), (1, 1));
+            // The spawn left over is plain land.
+            assert!(map[0][0].tile_type == TileType::Plain);
+            assert_eq!((map[0][0].unit, map[0][0].color), (0, 0));
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+
+use super::{
+    block::{Block, TileType},
+    map_generator::Map,
+};
+
+const TILE_STRIDE: usize = 6;
+
+// The tiles that changed between two versions of a map, flattened into groups of
+// [index, tile_type, unit, color, team, is_always_revealed] where index = x * height + y.
+// Patches are relative to a blank map (see map_generator::blank_map) when there is no
+// previous version, so a client rebuilds the map by applying every patch in order.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct MapPatch(pub Vec<i64>);
+
+pub fn diff_map(previous: &Map, current: &Map) -> MapPatch {
+    let height = current.first().map_or(0, |x| x.len());
+    let mut patch = Vec::new();
+
+    for block in current.iter().flatten() {
+        let (x, y) = (block.x as usize, block.y as usize);
+        let unchanged = match previous.get(x).and_then(|column| column.get(y)) {
+            Some(previous) => previous == block,
+            None => *block == Block::new(block.x, block.y, TileType::Plain, 0),
+        };
+        if !unchanged {
+            patch.extend_from_slice(&[
+                (x * height + y) as i64,
+                block.tile_type as i64,
+                block.unit,
+                block.color as i64,
+                block.team as i64,
+                block.is_always_revealed as i64,
+            ]);
+        }
+    }
+
+    MapPatch(patch)
+}
+
+pub fn apply_patch(map: &mut Map, patch: &MapPatch) -> Result<(), &'static str> {
+    let height = map.first().map_or(0, |x| x.len());
+    if !patch.0.len().is_multiple_of(TILE_STRIDE) || height == 0 {
+        return Err("Invalid map patch.");
+    }
+
+    for tile in patch.0.chunks_exact(TILE_STRIDE) {
+        let index = tile[0] as usize;
+        let block = map
+            .get_mut(index / height)
+            .and_then(|column| column.get_mut(index % height))
+            .ok_or("Invalid map patch.")?;
+        block.tile_type = TileType::try_from(tile[1] as u8)?;
+        block.unit = tile[2];
+        block.color = tile[3] as i16;
+        block.team = tile[4] as i16;
+        block.is_always_revealed = tile[5] != 0;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::map_generator::blank_map;
+
+    fn sample_map() -> Map {
+        let mut map = blank_map(4, 3);
+        map[0][0].init_king(1);
+        map[0][0].team = 1;
+        map[3][2].init_king(2);
+        map[3][2].team = 2;
+        map[1][2] = Block::new(1, 2, TileType::Mountain, 0);
+        map[2][1] = Block::new(2, 1, TileType::City, 42);
+        map[2][0].is_always_revealed = true;
+        map
+    }
+
+    #[test]
+    fn first_patch_rebuilds_from_a_blank_map() {
+        let map = sample_map();
+        let patch = diff_map(&Vec::new(), &map);
+        assert_eq!(patch.0.len(), 5 * TILE_STRIDE);
+
+        let mut rebuilt = blank_map(4, 3);
+        apply_patch(&mut rebuilt, &patch).unwrap();
+        assert_eq!(rebuilt, map);
+    }
+
+    #[test]
+    fn patches_replay_every_turn() {
+        let first = sample_map();
+        let mut second = first.clone();
+        second[0][0].unit = 3;
+        second[0][1].unit = 1;
+        second[0][1].dominated_by(1, 1);
+        let mut third = second.clone();
+        // The city changes owner.
+        third[2][1].unit = 5;
+        third[2][1].dominated_by(2, 2);
+
+        let maps = [first, second, third];
+        let mut rebuilt = blank_map(4, 3);
+        let mut previous = Vec::new();
+        for map in &maps {
+            apply_patch(&mut rebuilt, &diff_map(&previous, map)).unwrap();
+            assert_eq!(&rebuilt, map);
+            previous = map.clone();
+        }
+        assert_eq!(diff_map(&maps[1], &maps[2]).0.len(), TILE_STRIDE);
+        assert!(diff_map(&maps[2], &maps[2]).0.is_empty());
+    }
+
+    #[test]
+    fn malformed_patches_are_rejected() {
+        let mut map = blank_map(4, 3);
+        let truncated = MapPatch(vec![0, TileType::City as i64, 10, 0, 0]);
+        assert!(apply_patch(&mut map, &truncated).is_err());
+
+        let out_of_range = MapPatch(vec![12, TileType::City as i64, 10, 0, 0, 0]);
+        assert!(apply_patch(&mut map, &out_of_range).is_err());
+
+        let bad_tile = MapPatch(vec![0, 9, 10, 0, 0, 0]);
+        assert!(apply_patch(&mut map, &bad_tile).is_err());
+
+        assert_eq!(map, blank_map(4, 3));
+    }
+}
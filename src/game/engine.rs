@@ -0,0 +1,139 @@
+// The game rules on their own: no sockets, no database and no clock. Everything here is
+// synchronous and the same seed, options, players and commands always play out the same
+// game, so it can be driven by tests, offline simulations or tools. The server wraps these
+// types with its own per player Session, sockets, chat and replay recording.
+pub use super::{
+    block::{Block, TileType},
+    bot::BotDifficulty,
+    command::{Command, Move},
+    map_generator::Map,
+    player_in_room::PlayerInRoom,
+    room::{GameOptions, GameResult, Room, RoomState, TurnEvent},
+};
+
+// Starts a game on a generated map. Players are addressed by their index in Room::players
+// for Room::apply_command, and Room::tick plays one turn until the room is Finished.
+pub fn new_game(
+    mut options: GameOptions,
+    players: Vec<PlayerInRoom>,
+    seed: u64,
+) -> Result<Room, &'static str> {
+    options.seed = Some(seed);
+    let mut room = Room::new(options);
+    room.players = players;
+    room.generate_map()?;
+    room.set_state(RoomState::Playing)?;
+    Ok(room)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_TURNS: u32 = 3000;
+
+    fn player(color: usize, bot: Option<BotDifficulty>) -> PlayerInRoom {
+        PlayerInRoom {
+            username: format!("player {}", color),
+            color,
+            team: color,
+            bot,
+            ..Default::default()
+        }
+    }
+
+    fn king_of(room: &Room, color: i16) -> (i32, i32) {
+        let king = room
+            .map
+            .iter()
+            .flatten()
+            .find(|x| x.tile_type == TileType::King && x.color == color)
+            .unwrap();
+        (king.x, king.y)
+    }
+
+    // Plays a game between a scripted player and two bots until it is over. The scripted
+    // player keeps sending their king's army to the same side and gives up at turn 300.
+    fn simulate(seed: u64) -> (u32, Map, Vec<(bool, u32)>) {
+        let players = vec![
+            player(1, None),
+            player(2, Some(BotDifficulty::Normal)),
+            player(3, Some(BotDifficulty::Hard)),
+        ];
+        let mut room = new_game(GameOptions::default(), players, seed).unwrap();
+        let (x, y) = king_of(&room, 1);
+        let step = if x > 0 { (x - 1, y) } else { (x + 1, y) };
+
+        while room.state == RoomState::Playing && room.turn < MAX_TURNS {
+            if room.turn.is_multiple_of(10) {
+                let next = Move {
+                    from: (x, y),
+                    to: step,
+                    half: room.turn.is_multiple_of(20),
+                };
+                let _ = room.apply_command(0, Command::Move(next));
+            }
+            if room.turn == 300 {
+                let _ = room.apply_command(0, Command::Surrender);
+            }
+            room.tick();
+        }
+
+        let deaths = room
+            .players
+            .iter()
+            .map(|x| (x.is_dead, x.death_turn))
+            .collect();
+        (room.turn, room.map, deaths)
+    }
+
+    #[test]
+    fn same_seed_and_commands_play_the_same_game() {
+        let first = simulate(11);
+        assert_eq!(first, simulate(11));
+        assert!(first.0 < MAX_TURNS, "the game should end");
+        assert_eq!(first.2[0], (true, 300));
+
+        assert_ne!(first, simulate(12));
+    }
+
+    #[test]
+    fn moves_are_queued_and_played_by_the_next_tick() {
+        let players = vec![player(1, None), player(2, None)];
+        let mut room = new_game(GameOptions::default(), players, 5).unwrap();
+        let (x, y) = king_of(&room, 1);
+        let to = if x > 0 { (x - 1, y) } else { (x + 1, y) };
+
+        for _ in 0..5 {
+            room.tick();
+        }
+        let army = room.map[x as usize][y as usize].unit;
+        let next = Move {
+            from: (x, y),
+            to,
+            half: false,
+        };
+        room.apply_command(0, Command::Move(next)).unwrap();
+        assert_eq!(room.players[0].queue.len(), 1);
+
+        room.tick();
+        assert!(room.players[0].queue.is_empty());
+        let target = room.map[to.0 as usize][to.1 as usize];
+        assert_eq!((target.color, target.unit), (1, army - 1));
+        // The king keeps one unit and grows by one.
+        assert_eq!(room.map[x as usize][y as usize].unit, 2);
+    }
+
+    #[test]
+    fn commands_are_checked_against_the_room() {
+        let mut room = Room::new(GameOptions::default());
+        room.players = vec![player(1, None)];
+        let next = Move {
+            from: (0, 0),
+            to: (0, 1),
+            half: false,
+        };
+        assert!(room.apply_command(0, Command::Move(next)).is_err());
+        assert!(room.apply_command(1, Command::ClearQueue).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::VecDeque;
+
+use rand::{seq::SliceRandom, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use super::{
+    block::{Block, TileType},
+    constants::{
+        CITY_MAX_UNIT, CITY_MIN_UNIT, KING_PLACEMENT_ATTEMPTS, MAX_CITY_RATIO, MAX_MAP_SIZE,
+        MAX_MOUNTAIN_RATIO, MAX_SWAMP_RATIO, MIN_MAP_SIZE,
+    },
+    player_in_room::PlayerInRoom,
+    room::GameOptions,
+};
+
+pub type Map = Vec<Vec<Block>>;
+
+// Builds a random map from the room's sliders. The map is indexed as map[x][y].
+// The same seed, options and players always give the same map. ChaCha8Rng is used because,
+// unlike StdRng, its output is fixed, so stored seeds keep working after a rand update.
+pub fn generate_map<S>(
+    options: &GameOptions,
+    players: &[PlayerInRoom<S>],
+    seed: u64,
+) -> Result<Map, &'static str> {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+
+    let fighters: Vec<&PlayerInRoom<S>> = players.iter().filter(|x| !x.is_spectating()).collect();
+    if fighters.is_empty() {
+        return Err("No players to start the game.");
+    }
+
+    let width = map_dimension(options.map_width);
+    let height = map_dimension(options.map_height);
+    let mut map = blank_map(width, height);
+
+    let kings = place_kings(&mut map, &fighters, &mut rng)?;
+
+    let mut cells: Vec<(usize, usize)> = (0..width)
+        .flat_map(|x| (0..height).map(move |y| (x, y)))
+        .filter(|&(x, y)| map[x][y].tile_type == TileType::Plain)
+        .collect();
+    cells.shuffle(&mut rng);
+    let mut cells = cells.into_iter();
+
+    let total = (width * height) as f32;
+    let mountain_count = (total * options.mountain * MAX_MOUNTAIN_RATIO) as usize;
+    let city_count = (total * options.city * MAX_CITY_RATIO) as usize;
+    let swamp_count = (total * options.swamp * MAX_SWAMP_RATIO) as usize;
+
+    // A mountain is only kept if every king can still reach every other king.
+    let mut mountains = 0;
+    let mut skipped = Vec::new();
+    while mountains < mountain_count {
+        let Some((x, y)) = cells.next() else { break };
+        map[x][y].tile_type = TileType::Mountain;
+        if kings_connected(&map, &kings) {
+            mountains += 1;
+        } else {
+            map[x][y].tile_type = TileType::Plain;
+            skipped.push((x, y));
+        }
+    }
+    let mut cells = skipped.into_iter().chain(cells);
+
+    for (x, y) in cells.by_ref().take(city_count) {
+        map[x][y].tile_type = TileType::City;
+        map[x][y].unit = rng.gen_range(CITY_MIN_UNIT..=CITY_MAX_UNIT);
+    }
+    for (x, y) in cells.take(swamp_count) {
+        map[x][y].tile_type = TileType::Swamp;
+    }
+
+    Ok(map)
+}
+
+// A map of empty neutral plains.
+pub fn blank_map(width: usize, height: usize) -> Map {
+    (0..width)
+        .map(|x| {
+            (0..height)
+                .map(|y| Block::new(x as i32, y as i32, TileType::Plain, 0))
+                .collect()
+        })
+        .collect()
+}
+
+fn map_dimension(ratio: f32) -> usize {
+    MIN_MAP_SIZE + ((MAX_MAP_SIZE - MIN_MAP_SIZE) as f32 * ratio).round() as usize
+}
+
+// Kings are spread out as far as possible, relaxing the minimum distance when the map is crowded.
+fn place_kings<S>(
+    map: &mut Map,
+    fighters: &[&PlayerInRoom<S>],
+    rng: &mut impl Rng,
+) -> Result<Vec<(usize, usize)>, &'static str> {
+    let width = map.len();
+    let height = map[0].len();
+    let mut kings: Vec<(usize, usize)> = Vec::new();
+    let mut min_distance = (width + height) / 2;
+
+    for player in fighters {
+        loop {
+            let found = (0..KING_PLACEMENT_ATTEMPTS)
+                .map(|_| (rng.gen_range(0..width), rng.gen_range(0..height)))
+                .find(|&(x, y)| {
+                    kings
+                        .iter()
+                        .all(|&(kx, ky)| kx.abs_diff(x) + ky.abs_diff(y) >= min_distance)
+                });
+
+            match found {
+                Some((x, y)) => {
+                    map[x][y].init_king(player.color as i16);
+                    map[x][y].dominated_by(player.color as i16, player.team as i16);
+                    kings.push((x, y));
+                    break;
+                }
+                None if min_distance > 1 => min_distance -= 1,
+                None => return Err("Map is too small for all players."),
+            }
+        }
+    }
+
+    Ok(kings)
+}
+
+fn kings_connected(map: &Map, kings: &[(usize, usize)]) -> bool {
+    let reachable = reachable_from(map, kings[0]);
+    kings.iter().all(|&(x, y)| reachable[x][y])
+}
+
+fn reachable_from(map: &Map, (x, y): (usize, usize)) -> Vec<Vec<bool>> {
+    let mut visited = vec![vec![false; map[0].len()]; map.len()];
+    let mut queue = VecDeque::from([(x, y)]);
+    visited[x][y] = true;
+
+    while let Some((x, y)) = queue.pop_front() {
+        for (nx, ny) in neighbours(map, x, y) {
+            if !visited[nx][ny] && map[nx][ny].tile_type != TileType::Mountain {
+                visited[nx][ny] = true;
+                queue.push_back((nx, ny));
+            }
+        }
+    }
+
+    visited
+}
+
+pub fn neighbours(map: &Map, x: usize, y: usize) -> impl Iterator<Item = (usize, usize)> {
+    let width = map.len() as i64;
+    let height = map[0].len() as i64;
+    [(-1, 0), (1, 0), (0, -1), (0, 1)]
+        .into_iter()
+        .map(move |(dx, dy)| (x as i64 + dx, y as i64 + dy))
+        .filter(move |&(x, y)| x >= 0 && x < width && y >= 0 && y < height)
+        .map(|(x, y)| (x as usize, y as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn players(count: usize) -> Vec<PlayerInRoom> {
+        (1..=count)
+            .map(|i| PlayerInRoom {
+                color: i,
+                team: i,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn kings(map: &Map) -> Vec<(i32, i32, i16)> {
+        map.iter()
+            .flatten()
+            .filter(|x| x.tile_type == TileType::King)
+            .map(|x| (x.x, x.y, x.color))
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_map() {
+        let options = GameOptions {
+            swamp: 0.5,
+            ..Default::default()
+        };
+        let first = generate_map(&options, &players(4), 42).unwrap();
+        let second = generate_map(&options, &players(4), 42).unwrap();
+        assert_eq!(first, second);
+
+        let other = generate_map(&options, &players(4), 43).unwrap();
+        assert_ne!(first, other);
+    }
+
+    // Seeds are stored with replays, so the maps they give must never change.
+    #[test]
+    fn seeds_keep_their_map() {
+        let map = generate_map(&GameOptions::default(), &players(2), 7).unwrap();
+        assert_eq!(kings(&map), vec![(4, 17, 1), (22, 3, 2)]);
+        let city_units: i64 = map
+            .iter()
+            .flatten()
+            .filter(|x| x.tile_type == TileType::City)
+            .map(|x| x.unit)
+            .sum();
+        assert_eq!(city_units, 1108);
+    }
+}
@@ -0,0 +1,218 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use serde::Serialize;
+use socketioxide::{extract::SocketRef, socket::Sid};
+use tokio::sync::{mpsc, RwLock};
+
+use super::{
+    constants::TURN_INTERVAL_MS,
+    diff::{apply_patch, diff_map, MapPatch},
+    map_generator::{blank_map, Map},
+    replay::rebuild_map,
+};
+
+pub enum PlaybackCommand {
+    Play,
+    Pause,
+    Speed(i32),
+    Seek(u32),
+}
+
+// The replay a socket is streaming and the channel to its playback task.
+type PlaybackSession = (String, mpsc::UnboundedSender<PlaybackCommand>);
+
+// One playback per socket, keyed by the replay it is streaming.
+#[derive(Clone, Default)]
+pub struct PlaybackStore {
+    pub sessions: Arc<RwLock<HashMap<Sid, PlaybackSession>>>,
+}
+
+impl PlaybackStore {
+    pub async fn is_playing(&self, socket_id: Sid, replay_id: &str) -> bool {
+        let binding = self.sessions.read().await;
+        matches!(binding.get(&socket_id), Some((id, _)) if id == replay_id)
+    }
+
+    // Starts streaming to the socket, replacing the replay it was watching before.
+    pub async fn start(&self, socket: SocketRef, replay_id: String, playback: Playback) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut binding = self.sessions.write().await;
+        binding.insert(socket.id, (replay_id, sender));
+        tokio::spawn(run_playback(socket, playback, receiver));
+    }
+
+    pub async fn command(
+        &self,
+        socket_id: Sid,
+        command: PlaybackCommand,
+    ) -> Result<(), &'static str> {
+        let binding = self.sessions.read().await;
+        match binding.get(&socket_id) {
+            Some((_, sender)) => sender.send(command).map_err(|_| "Replay not playing."),
+            None => Err("Replay not playing."),
+        }
+    }
+
+    // Dropping the sender ends the playback task.
+    pub async fn stop(&self, socket_id: Sid) {
+        let mut binding = self.sessions.write().await;
+        binding.remove(&socket_id);
+    }
+}
+
+// A frame of the replay. When `reset` is set the patch is relative to a blank
+// width x height map, otherwise to the previous frame.
+#[derive(Serialize)]
+pub struct ReplayFrame {
+    turn: u32,
+    width: usize,
+    height: usize,
+    reset: bool,
+    patch: MapPatch,
+}
+
+#[derive(Serialize)]
+pub struct ReplayMessage {
+    turn: u32,
+    username: String,
+    content: String,
+}
+
+// A stored replay being rebuilt turn by turn from its map diffs.
+pub struct Playback {
+    width: usize,
+    height: usize,
+    diffs: Vec<(u32, MapPatch)>,
+    keyframes: Vec<(u32, MapPatch)>,
+    messages: Vec<ReplayMessage>,
+    map: Map,
+    // Index of the next diff and message to play.
+    cursor: usize,
+    message_cursor: usize,
+}
+
+impl Playback {
+    // `diffs`, `keyframes` and `messages` must be ordered by turn.
+    pub fn new(
+        width: usize,
+        height: usize,
+        diffs: Vec<(u32, MapPatch)>,
+        keyframes: Vec<(u32, MapPatch)>,
+        messages: Vec<(u32, String, String)>,
+    ) -> Self {
+        Playback {
+            width,
+            height,
+            diffs,
+            keyframes,
+            messages: messages
+                .into_iter()
+                .map(|(turn, username, content)| ReplayMessage {
+                    turn,
+                    username,
+                    content,
+                })
+                .collect(),
+            map: blank_map(width, height),
+            cursor: 0,
+            message_cursor: 0,
+        }
+    }
+
+    // Plays the next recorded turn with the chat sent during it. None once the replay ended.
+    pub fn step(&mut self) -> Option<(ReplayFrame, &[ReplayMessage])> {
+        let (turn, patch) = self.diffs.get(self.cursor)?;
+        let turn = *turn;
+        if apply_patch(&mut self.map, patch).is_err() {
+            return None;
+        }
+        self.cursor += 1;
+
+        let first = self.message_cursor;
+        while self
+            .messages
+            .get(self.message_cursor)
+            .is_some_and(|x| x.turn <= turn)
+        {
+            self.message_cursor += 1;
+        }
+
+        let frame = ReplayFrame {
+            turn,
+            width: self.width,
+            height: self.height,
+            reset: false,
+            patch: patch.clone(),
+        };
+        Some((frame, &self.messages[first..self.message_cursor]))
+    }
+
+    // Rebuilds the map as it was at the end of `turn`, starting from the closest keyframe.
+    pub fn seek(&mut self, turn: u32) -> ReplayFrame {
+        match rebuild_map(self.width, self.height, &self.keyframes, &self.diffs, turn) {
+            Ok((map, cursor)) => {
+                self.map = map;
+                self.cursor = cursor;
+            }
+            // Corrupted data, play nothing more.
+            Err(_) => {
+                self.map = blank_map(self.width, self.height);
+                self.cursor = self.diffs.len();
+            }
+        }
+        self.message_cursor = self.messages.partition_point(|x| x.turn <= turn);
+
+        ReplayFrame {
+            turn,
+            width: self.width,
+            height: self.height,
+            reset: true,
+            patch: diff_map(&Vec::new(), &self.map),
+        }
+    }
+}
+
+fn frame_interval(speed: i32) -> Duration {
+    Duration::from_secs_f32(TURN_INTERVAL_MS / speed as f32 / 1000.0)
+}
+
+async fn run_playback(
+    socket: SocketRef,
+    mut playback: Playback,
+    mut commands: mpsc::UnboundedReceiver<PlaybackCommand>,
+) {
+    let mut paused = false;
+    let mut interval = tokio::time::interval(frame_interval(1));
+
+    loop {
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(PlaybackCommand::Play) => paused = false,
+                Some(PlaybackCommand::Pause) => paused = true,
+                Some(PlaybackCommand::Speed(speed)) => {
+                    interval = tokio::time::interval(frame_interval(speed));
+                }
+                Some(PlaybackCommand::Seek(turn)) => {
+                    if socket.emit("replay:update", playback.seek(turn)).is_err() {
+                        break;
+                    }
+                }
+                None => break,
+            },
+            _ = interval.tick(), if !paused => match playback.step() {
+                Some((frame, messages)) => {
+                    if socket.emit("replay:update", frame).is_err() {
+                        break;
+                    }
+                    for message in messages {
+                        let _ = socket.emit("replay:message", message);
+                    }
+                }
+                None => {
+                    paused = true;
+                    let _ = socket.emit("replay:end", ());
+                }
+            },
+        }
+    }
+}
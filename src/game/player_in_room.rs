@@ -1,29 +1,45 @@
+use std::collections::VecDeque;
+
 use serde::Serialize;
-use socketioxide::socket::Sid;
 
-use super::{block::Block, constants::MAX_TEAM_NUM};
+use super::{bot::BotDifficulty, command::Move, constants::MAX_TEAM_NUM};
 
-#[derive(Serialize, Clone, Default, PartialEq)]
-pub struct PlayerInRoom {
+// Holds secrets (player_id) and game state, so it is never sent as is. `session` is whatever
+// the host keeps about the player's connection, the engine itself never looks at it.
+#[derive(Clone, Default, PartialEq)]
+pub struct PlayerInRoom<S = ()> {
     pub player_id: String,
     pub username: String,
-    pub socket_id: Sid,
     pub color: usize,
     pub team: usize,
     pub is_room_host: bool,
     pub force_start: bool,
     pub is_dead: bool,
+    pub death_turn: u32,
     pub last_operate_turn: u32,
-    pub land: Vec<Block>,
+    pub queue: VecDeque<Move>,
+    // Set for players played by the server, which have no socket.
+    pub bot: Option<BotDifficulty>,
+    pub session: S,
 }
 
-#[derive(Serialize, Default)]
+#[derive(Serialize, Default, Clone)]
 pub struct MinifiedPlayer {
     username: String,
     color: usize,
 }
 
-impl PlayerInRoom {
+#[derive(Serialize)]
+pub struct PublicPlayer {
+    username: String,
+    color: usize,
+    team: usize,
+    is_room_host: bool,
+    force_start: bool,
+    is_dead: bool,
+}
+
+impl<S> PlayerInRoom<S> {
     pub fn minify(&self) -> MinifiedPlayer {
         MinifiedPlayer {
             username: self.username.clone(),
@@ -31,6 +47,17 @@ impl PlayerInRoom {
         }
     }
 
+    pub fn to_public(&self) -> PublicPlayer {
+        PublicPlayer {
+            username: self.username.clone(),
+            color: self.color,
+            team: self.team,
+            is_room_host: self.is_room_host,
+            force_start: self.force_start,
+            is_dead: self.is_dead,
+        }
+    }
+
     pub fn set_spectate(&mut self) {
         (*self).team = MAX_TEAM_NUM + 1;
     }
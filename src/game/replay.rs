@@ -0,0 +1,215 @@
+use serde::Serialize;
+
+use super::{
+    constants::REPLAY_KEYFRAME_INTERVAL,
+    diff::{apply_patch, diff_map, MapPatch},
+    map_generator::{blank_map, Map},
+};
+
+#[derive(Clone)]
+pub struct RecordedMessage {
+    pub turn: i32,
+    pub sender_id: String,
+    pub content: String,
+}
+
+// Rows waiting to be written, map data is a JSON encoded MapPatch.
+#[derive(Clone, Default)]
+pub struct ReplayRecords {
+    pub diffs: Vec<(i32, String)>,
+    pub keyframes: Vec<(i32, String)>,
+    pub messages: Vec<RecordedMessage>,
+}
+
+// Collects a running game's map diffs, keyframes and chat until they are flushed into the
+// Replay's MapDiff, MapKeyframe and Message rows.
+#[derive(Clone)]
+pub struct ReplayRecorder {
+    pub replay_id: String,
+    previous: Map,
+    records: ReplayRecords,
+}
+
+impl ReplayRecorder {
+    pub fn new(replay_id: String) -> Self {
+        ReplayRecorder {
+            replay_id,
+            previous: Vec::new(),
+            records: ReplayRecords::default(),
+        }
+    }
+
+    // Stores the patch from the last recorded turn, the first call patches a blank map.
+    // Every REPLAY_KEYFRAME_INTERVAL turns the whole map is stored as well.
+    pub fn record_turn(&mut self, turn: u32, map: &Map) {
+        let patch = diff_map(&self.previous, map);
+        self.records.diffs.push((
+            turn as i32,
+            serde_json::to_string(&patch).unwrap_or_default(),
+        ));
+        if turn > 0 && turn.is_multiple_of(REPLAY_KEYFRAME_INTERVAL) {
+            let keyframe = diff_map(&Vec::new(), map);
+            self.records.keyframes.push((
+                turn as i32,
+                serde_json::to_string(&keyframe).unwrap_or_default(),
+            ));
+        }
+        self.previous = map.clone();
+    }
+
+    pub fn record_message(&mut self, turn: u32, sender_id: String, content: String) {
+        self.records.messages.push(RecordedMessage {
+            turn: turn as i32,
+            sender_id,
+            content,
+        });
+    }
+
+    // Takes everything recorded since the last flush.
+    pub fn drain(&mut self) -> ReplayRecords {
+        std::mem::take(&mut self.records)
+    }
+}
+
+// The map of a replay at the end of a turn.
+#[derive(Serialize)]
+pub struct ReplaySnapshot {
+    turn: u32,
+    width: usize,
+    height: usize,
+    map: Map,
+}
+
+pub fn parse_records(rows: Vec<(i32, String)>) -> Result<Vec<(u32, MapPatch)>, &'static str> {
+    rows.into_iter()
+        .map(|(turn, data)| Ok((turn as u32, serde_json::from_str(&data)?)))
+        .collect::<Result<_, serde_json::Error>>()
+        .map_err(|_| "Replay data is corrupted.")
+}
+
+// Rebuilds the map at the end of `turn` from the closest keyframe before it and the diffs
+// recorded since. Both lists must be ordered by turn. Also returns the index of the first
+// diff after `turn`.
+pub fn rebuild_map(
+    width: usize,
+    height: usize,
+    keyframes: &[(u32, MapPatch)],
+    diffs: &[(u32, MapPatch)],
+    turn: u32,
+) -> Result<(Map, usize), &'static str> {
+    let mut map = blank_map(width, height);
+    let mut start = 0;
+    if let Some((keyframe_turn, keyframe)) = keyframes.iter().rev().find(|x| x.0 <= turn) {
+        apply_patch(&mut map, keyframe)?;
+        start = diffs.partition_point(|x| x.0 <= *keyframe_turn);
+    }
+
+    let end = diffs.partition_point(|x| x.0 <= turn);
+    for (_, patch) in &diffs[start..end] {
+        apply_patch(&mut map, patch)?;
+    }
+    Ok((map, end))
+}
+
+// Used by the replays API, which only loads the rows needed for `turn`.
+pub fn replay_snapshot(
+    width: usize,
+    height: usize,
+    turn: u32,
+    keyframes: Vec<(i32, String)>,
+    diffs: Vec<(i32, String)>,
+) -> Result<ReplaySnapshot, &'static str> {
+    let keyframes = parse_records(keyframes)?;
+    let diffs = parse_records(diffs)?;
+    let (map, _) = rebuild_map(width, height, &keyframes, &diffs, turn)?;
+
+    Ok(ReplaySnapshot {
+        turn,
+        width,
+        height,
+        map,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::block::{Block, TileType};
+
+    // Records `turns` turns of a game where a king grows and an army walks along row 0.
+    fn record_game(turns: u32) -> (Vec<Map>, ReplayRecords) {
+        let mut map = blank_map(6, 4);
+        map[0][0].init_king(1);
+        map[2][2] = Block::new(2, 2, TileType::Mountain, 0);
+        map[5][3] = Block::new(5, 3, TileType::City, 40);
+
+        let mut recorder = ReplayRecorder::new("replay".to_string());
+        let mut maps = Vec::new();
+        for turn in 0..=turns {
+            if turn > 0 {
+                map[0][0].unit += 1;
+                let x = turn as usize % 5 + 1;
+                map[x][0].unit = turn as i64;
+                map[x][0].dominated_by(1, 1);
+            }
+            recorder.record_turn(turn, &map);
+            maps.push(map.clone());
+        }
+        (maps, recorder.drain())
+    }
+
+    type Rows = Vec<(i32, String)>;
+
+    // Picks the rows the replays API loads for `turn`.
+    fn rows_for(records: &ReplayRecords, turn: u32) -> (Rows, Rows) {
+        let keyframe: Rows = records
+            .keyframes
+            .iter()
+            .rev()
+            .find(|x| x.0 <= turn as i32)
+            .cloned()
+            .into_iter()
+            .collect();
+        let from = keyframe.first().map_or(-1, |x| x.0);
+        let diffs = records
+            .diffs
+            .iter()
+            .filter(|x| x.0 > from && x.0 <= turn as i32)
+            .cloned()
+            .collect();
+        (keyframe, diffs)
+    }
+
+    #[test]
+    fn snapshot_before_the_first_keyframe_keeps_the_initial_map() {
+        let (maps, records) = record_game(REPLAY_KEYFRAME_INTERVAL - 1);
+        let (keyframes, diffs) = rows_for(&records, 10);
+        assert!(keyframes.is_empty());
+
+        let snapshot = replay_snapshot(6, 4, 10, keyframes, diffs).unwrap();
+        assert_eq!(snapshot.map, maps[10]);
+        assert!(snapshot.map[2][2].tile_type == TileType::Mountain);
+        assert!(snapshot.map[5][3].tile_type == TileType::City);
+    }
+
+    #[test]
+    fn snapshot_starts_from_the_latest_keyframe() {
+        let (maps, records) = record_game(REPLAY_KEYFRAME_INTERVAL * 2 + 5);
+        let last = REPLAY_KEYFRAME_INTERVAL * 2 + 3;
+        for turn in [0, 1, REPLAY_KEYFRAME_INTERVAL, last] {
+            let (keyframes, diffs) = rows_for(&records, turn);
+            let snapshot = replay_snapshot(6, 4, turn, keyframes, diffs).unwrap();
+            assert_eq!(snapshot.map, maps[turn as usize]);
+        }
+    }
+
+    #[test]
+    fn rebuild_map_without_keyframes_applies_every_diff() {
+        let (maps, records) = record_game(20);
+        let diffs = parse_records(records.diffs).unwrap();
+
+        let (map, next) = rebuild_map(6, 4, &[], &diffs, 12).unwrap();
+        assert_eq!(map, maps[12]);
+        assert_eq!(next, 13);
+    }
+}
@@ -1,6 +1,23 @@
+use std::{collections::HashMap, time::Duration};
+
+use rand::{thread_rng, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use serde::Serialize;
 
-use super::{block::Block, player_in_room::PlayerInRoom};
+use super::{
+    block::Block,
+    bot::next_move,
+    command::{Command, MoveResult},
+    constants::{
+        AFK_WARNING_TURNS, COLOR_ARR, DEFAULT_AFK_TURNS, FORCE_START_OK, MAX_TEAM_NUM,
+        TURN_INTERVAL_MS,
+    },
+    custom_map::load_custom_map,
+    map_generator::{generate_map, Map},
+    player_in_room::{PlayerInRoom, PublicPlayer},
+    rules::{apply_growth, capture_king, surrender},
+    view::player_view,
+};
 
 #[derive(Serialize, Clone)]
 pub struct GameOptions {
@@ -18,18 +35,117 @@ pub struct GameOptions {
     pub death_spectating: bool,
     pub reveal_king: bool,
     pub warring_state: bool,
+    // What happens to the land of a player who surrenders: turn neutral or stay theirs, inert.
+    pub neutral_surrender: bool,
+    // Turns without a command before a player is surrendered, 0 turns the check off.
+    pub afk_turns: u32,
+    // Fixed map seed, a random one is picked for every game when unset. It gives away the
+    // whole map, so clients only learn whether one is set.
+    #[serde(skip)]
+    pub seed: Option<u64>,
+}
+
+impl Default for GameOptions {
+    fn default() -> Self {
+        GameOptions {
+            room_name: "Untitled".to_string(),
+            map_id: "".to_string(),
+            map_name: "".to_string(),
+            max_players: 8,
+            game_speed: 1.0,
+            map_width: 0.5,
+            map_height: 0.5,
+            mountain: 0.5,
+            city: 0.5,
+            swamp: 0.0,
+            fog_of_war: true,
+            death_spectating: true,
+            reveal_king: false,
+            warring_state: false,
+            neutral_surrender: true,
+            afk_turns: DEFAULT_AFK_TURNS,
+            seed: None,
+        }
+    }
 }
 
-#[derive(Clone, Serialize)]
-pub struct Room {
+// The state of a game and its lobby. Clients get a PublicRoom in the lobby and a GameUpdate
+// in game. `S` is the per player session of the host, see PlayerInRoom.
+#[derive(Clone)]
+pub struct Room<S = ()> {
     pub game_options: GameOptions,
     pub force_start_num: usize,
-    pub game_started: bool,
-    pub map_generated: bool,
-    pub players: Vec<PlayerInRoom>,
+    pub state: RoomState,
+    // Bumped for every countdown, so a stale one can tell it was cancelled.
+    pub countdown_token: u32,
+    pub turn: u32,
+    // Seed the current map was generated from.
+    pub seed: u64,
+    pub players: Vec<PlayerInRoom<S>>,
     pub map: Vec<Vec<Block>>,
 }
 
+// Lobby -> Countdown -> Playing -> Finished -> Lobby. A cancelled countdown goes back to the
+// lobby, and games run without a countdown (e.g. through the engine) start from the lobby.
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomState {
+    Lobby,
+    Countdown,
+    Playing,
+    Finished,
+}
+
+impl RoomState {
+    fn can_become(self, next: RoomState) -> bool {
+        matches!(
+            (self, next),
+            (RoomState::Lobby, RoomState::Countdown)
+                | (RoomState::Lobby, RoomState::Playing)
+                | (RoomState::Countdown, RoomState::Lobby)
+                | (RoomState::Countdown, RoomState::Playing)
+                | (RoomState::Playing, RoomState::Finished)
+                | (RoomState::Finished, RoomState::Lobby)
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct PublicRoom {
+    game_options: GameOptions,
+    force_start_num: usize,
+    state: RoomState,
+    game_started: bool,
+    seed_set: bool,
+    players: Vec<PublicPlayer>,
+}
+
+// Something that happened during a turn which the game loop has to announce.
+// Players are referred to by their index in Room::players.
+pub enum TurnEvent {
+    Captured { captor: usize, victim: usize },
+    AfkWarning { player: usize, turns_left: u32 },
+    AfkSurrendered(usize),
+    GameOver(GameResult),
+}
+
+#[derive(Serialize)]
+pub struct GameResult {
+    winner_team: Option<usize>,
+    // Winners first, then the other players by how long they survived.
+    rankings: Vec<PublicPlayer>,
+}
+
+#[derive(Serialize)]
+pub struct LeaderboardEntry {
+    username: String,
+    color: usize,
+    team: usize,
+    army: i64,
+    land: usize,
+    is_dead: bool,
+}
+
 #[derive(Serialize)]
 pub struct MinifiedRoom {
     id: String,
@@ -40,15 +156,533 @@ pub struct MinifiedRoom {
     max_players: usize,
 }
 
-impl Room {
+impl<S> Room<S> {
+    pub fn new(game_options: GameOptions) -> Self {
+        Room {
+            game_options,
+            force_start_num: 0,
+            state: RoomState::Lobby,
+            countdown_token: 0,
+            turn: 0,
+            seed: 0,
+            players: Vec::new(),
+            map: Vec::new(),
+        }
+    }
+
+    pub fn set_state(&mut self, next: RoomState) -> Result<(), &'static str> {
+        if !self.state.can_become(next) {
+            return Err("Invalid room state.");
+        }
+        self.state = next;
+        Ok(())
+    }
+
+    // Players can still join, vote and change the options.
+    pub fn in_lobby(&self) -> bool {
+        matches!(self.state, RoomState::Lobby | RoomState::Countdown)
+    }
+
+    // Lowest color not taken yet, colors start at 1 since 0 is neutral.
+    pub fn free_color(&self) -> Option<usize> {
+        (1..COLOR_ARR.len()).find(|&i| self.players.iter().all(|x| x.color != i))
+    }
+
+    pub fn free_team(&self) -> Option<usize> {
+        (1..=MAX_TEAM_NUM).find(|&i| self.players.iter().all(|x| x.team != i))
+    }
+
+    // Recounts the votes of the players who will play. Returns whether the game may start:
+    // enough of them voted, or the room is full with more than one of them. Bots always vote,
+    // so a room needs a human among them to start.
+    pub fn update_votes(&mut self) -> bool {
+        let fighters: Vec<&PlayerInRoom<S>> =
+            self.players.iter().filter(|x| !x.is_spectating()).collect();
+        self.force_start_num = fighters.iter().filter(|x| x.force_start).count();
+
+        let needed = FORCE_START_OK[usize::min(fighters.len(), FORCE_START_OK.len() - 1)];
+        let full = self.players.len() >= self.game_options.max_players && fighters.len() > 1;
+        let human = fighters.iter().any(|x| x.bot.is_none());
+        human && (self.force_start_num as i32 >= needed || full)
+    }
+
+    // Hands the room over to the first human player when the host left.
+    pub fn ensure_host(&mut self) {
+        if self.players.iter().any(|x| x.is_room_host) {
+            return;
+        }
+        if let Some(player) = self.players.iter_mut().find(|x| x.bot.is_none()) {
+            player.is_room_host = true;
+        }
+    }
+
     pub fn minify(&self, id: String) -> MinifiedRoom {
         MinifiedRoom {
             id,
             room_name: self.game_options.room_name.clone(),
-            game_started: self.game_started,
+            game_started: !self.in_lobby(),
             game_speed: self.game_options.game_speed,
             player_count: self.players.len(),
             max_players: self.game_options.max_players,
         }
     }
-}
\ No newline at end of file
+
+    pub fn to_public(&self) -> PublicRoom {
+        PublicRoom {
+            game_options: self.game_options.clone(),
+            force_start_num: self.force_start_num,
+            state: self.state,
+            game_started: !self.in_lobby(),
+            seed_set: self.game_options.seed.is_some(),
+            players: self.players.iter().map(|x| x.to_public()).collect(),
+        }
+    }
+
+    // Ownership is read from the map itself, so the scores can never go stale.
+    pub fn leaderboard(&self) -> Vec<LeaderboardEntry> {
+        let mut owned: HashMap<i16, (i64, usize)> = HashMap::new();
+        for block in self.map.iter().flatten().filter(|x| x.color != 0) {
+            let entry = owned.entry(block.color).or_default();
+            entry.0 += block.unit;
+            entry.1 += 1;
+        }
+
+        let mut leaderboard: Vec<LeaderboardEntry> = self
+            .players
+            .iter()
+            .filter(|x| !x.is_spectating())
+            .map(|x| {
+                let (army, land) = owned.get(&(x.color as i16)).copied().unwrap_or_default();
+                LeaderboardEntry {
+                    username: x.username.clone(),
+                    color: x.color,
+                    team: x.team,
+                    army,
+                    land,
+                    is_dead: x.is_dead,
+                }
+            })
+            .collect();
+        leaderboard.sort_by(|a, b| b.army.cmp(&a.army).then(b.land.cmp(&a.land)));
+        leaderboard
+    }
+
+    pub fn generate_map(&mut self) -> Result<(), &'static str> {
+        self.pick_seed();
+        self.map = generate_map(&self.game_options, &self.players, self.seed)?;
+        Ok(())
+    }
+
+    pub fn load_custom_map(
+        &mut self,
+        tiles_data: &str,
+        width: usize,
+        height: usize,
+    ) -> Result<(), &'static str> {
+        self.pick_seed();
+        self.map = load_custom_map(tiles_data, width, height, &self.players, self.seed)?;
+        Ok(())
+    }
+
+    fn pick_seed(&mut self) {
+        self.seed = self.game_options.seed.unwrap_or_else(|| thread_rng().gen());
+    }
+
+    // Spectators, and dead players when death spectating is on, see the whole map. Everyone
+    // else sees what their team sees.
+    pub fn player_view(&self, player: &PlayerInRoom<S>) -> Map {
+        let full_vision =
+            player.is_spectating() || (player.is_dead && self.game_options.death_spectating);
+        player_view(
+            &self.map,
+            &self.game_options,
+            player.team as i16,
+            full_vision,
+        )
+    }
+
+    pub fn turn_interval(&self) -> Duration {
+        Duration::from_secs_f32(TURN_INTERVAL_MS / self.game_options.game_speed / 1000.0)
+    }
+
+    // Advance the game by one turn. Called by the room's game loop, does nothing unless the
+    // room is Playing.
+    pub fn tick(&mut self) -> Vec<TurnEvent> {
+        if self.state != RoomState::Playing {
+            return Vec::new();
+        }
+        self.turn += 1;
+        self.run_bots();
+        let mut events = self.process_moves();
+        events.extend(self.check_afk());
+        let inert: Vec<i16> = self
+            .players
+            .iter()
+            .filter(|x| x.is_dead)
+            .map(|x| x.color as i16)
+            .collect();
+        apply_growth(&mut self.map, self.turn, &inert);
+        if let Some(result) = self.check_game_over() {
+            events.push(TurnEvent::GameOver(result));
+        }
+        events
+    }
+
+    // Resolves at most one queued move per player. The player who moves first rotates
+    // every turn so nobody always wins the race for a tile.
+    fn process_moves(&mut self) -> Vec<TurnEvent> {
+        let mut events = Vec::new();
+        let player_count = self.players.len();
+        if player_count == 0 {
+            return events;
+        }
+        let offset = self.turn as usize % player_count;
+
+        for i in 0..player_count {
+            let index = (i + offset) % player_count;
+            let player = &mut self.players[index];
+            if player.is_dead || player.is_spectating() {
+                player.queue.clear();
+                continue;
+            }
+
+            let (color, team) = (player.color as i16, player.team as i16);
+            while let Some(next) = player.queue.pop_front() {
+                match next.execute(&mut self.map, color, team) {
+                    MoveResult::Invalid => continue,
+                    MoveResult::Moved => break,
+                    MoveResult::CapturedKing(victim) => {
+                        let king = (next.to.0 as usize, next.to.1 as usize);
+                        if let Some(victim) = self.eliminate(king, victim, index) {
+                            events.push(TurnEvent::Captured {
+                                captor: index,
+                                victim,
+                            });
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+
+        events
+    }
+
+    // Kills the player owning `victim_color` and gives their land to the captor.
+    // Returns the index of the eliminated player.
+    fn eliminate(
+        &mut self,
+        king: (usize, usize),
+        victim_color: i16,
+        captor: usize,
+    ) -> Option<usize> {
+        let (color, team) = (
+            self.players[captor].color as i16,
+            self.players[captor].team as i16,
+        );
+        capture_king(&mut self.map, king, victim_color, color, team);
+
+        let victim = self
+            .players
+            .iter()
+            .position(|x| x.color as i16 == victim_color && !x.is_dead)?;
+        self.players[victim].is_dead = true;
+        self.players[victim].death_turn = self.turn;
+        self.players[victim].queue.clear();
+        Some(victim)
+    }
+
+    // Bots decide from their own fogged view and go through apply_command like everyone else.
+    fn run_bots(&mut self) {
+        for index in 0..self.players.len() {
+            let player = &self.players[index];
+            let Some(difficulty) = player.bot else {
+                continue;
+            };
+            if player.is_dead || player.is_spectating() || !player.queue.is_empty() {
+                continue;
+            }
+
+            let view = self.player_view(player);
+            let mut rng =
+                ChaCha8Rng::seed_from_u64(self.seed ^ ((self.turn as u64) << 8) ^ index as u64);
+            let (color, team) = (player.color as i16, player.team as i16);
+            if let Some(next) = next_move(&view, color, team, difficulty, self.turn, &mut rng) {
+                let _ = self.apply_command(index, Command::Move(next));
+            }
+        }
+    }
+
+    // Warns players idle for a while and surrenders them once they reach afk_turns.
+    fn check_afk(&mut self) -> Vec<TurnEvent> {
+        let mut events = Vec::new();
+        let afk_turns = self.game_options.afk_turns;
+        if afk_turns == 0 {
+            return events;
+        }
+
+        for index in 0..self.players.len() {
+            let player = &self.players[index];
+            if player.is_dead || player.is_spectating() || player.bot.is_some() {
+                continue;
+            }
+            let idle = self.turn.saturating_sub(player.last_operate_turn);
+            if idle >= afk_turns {
+                self.surrender(index);
+                events.push(TurnEvent::AfkSurrendered(index));
+            } else if idle + AFK_WARNING_TURNS == afk_turns {
+                events.push(TurnEvent::AfkWarning {
+                    player: index,
+                    turns_left: AFK_WARNING_TURNS,
+                });
+            }
+        }
+        events
+    }
+
+    // Applies a command from the player at `index`. Moves are only queued here and
+    // executed by the following ticks.
+    pub fn apply_command(&mut self, index: usize, command: Command) -> Result<(), &'static str> {
+        let started = self.state == RoomState::Playing;
+        let turn = self.turn;
+        let player = self.players.get_mut(index).ok_or("Player not found.")?;
+        let playing = started && !player.is_dead && !player.is_spectating();
+
+        match command {
+            Command::Move(next) => {
+                if !started {
+                    return Err("Game not started.");
+                }
+                next.validate(&self.map)?;
+                if !playing {
+                    return Err("Player can't move.");
+                }
+                player.queue.push_back(next);
+            }
+            // Queues only exist while a game is running.
+            Command::ClearQueue | Command::PopQueue if !started => {
+                return Err("Game not started.");
+            }
+            Command::ClearQueue => player.queue.clear(),
+            Command::PopQueue => {
+                player.queue.pop_back();
+            }
+            Command::Surrender => {
+                if !started {
+                    return Err("Game not started.");
+                }
+                if !playing {
+                    return Err("Player can't surrender.");
+                }
+                self.surrender(index);
+                return Ok(());
+            }
+        }
+        player.last_operate_turn = turn;
+        Ok(())
+    }
+
+    // Takes the player out of the game. The game over check happens on the next tick.
+    pub fn surrender(&mut self, index: usize) {
+        let player = &mut self.players[index];
+        player.is_dead = true;
+        player.death_turn = self.turn;
+        player.queue.clear();
+        let color = player.color as i16;
+        surrender(&mut self.map, color, self.game_options.neutral_surrender);
+    }
+
+    // The game is over once every surviving player is on the same team.
+    fn check_game_over(&mut self) -> Option<GameResult> {
+        let mut alive = self
+            .players
+            .iter()
+            .filter(|x| !x.is_dead && !x.is_spectating());
+        let winner_team = alive.next().map(|x| x.team);
+        if alive.any(|x| Some(x.team) != winner_team) {
+            return None;
+        }
+
+        let mut ranked: Vec<&PlayerInRoom<S>> =
+            self.players.iter().filter(|x| !x.is_spectating()).collect();
+        ranked.sort_by_key(|x| {
+            (
+                Some(x.team) != winner_team,
+                x.is_dead,
+                std::cmp::Reverse(x.death_turn),
+            )
+        });
+        let result = GameResult {
+            winner_team,
+            rankings: ranked.iter().map(|x| x.to_public()).collect(),
+        };
+
+        self.set_state(RoomState::Finished).ok()?;
+        Some(result)
+    }
+
+    // Brings a finished room back to the lobby so another round can be started.
+    pub fn reset(&mut self) -> Result<(), &'static str> {
+        self.set_state(RoomState::Lobby)?;
+        for player in self.players.iter_mut() {
+            player.force_start = player.bot.is_some();
+            player.is_dead = false;
+            player.death_turn = 0;
+            player.last_operate_turn = 0;
+            player.queue.clear();
+        }
+        self.update_votes();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{bot::BotDifficulty, map_generator::blank_map};
+
+    fn player(color: usize) -> PlayerInRoom {
+        PlayerInRoom {
+            color,
+            team: color,
+            ..Default::default()
+        }
+    }
+
+    fn room(players: usize) -> Room {
+        let mut room = Room::new(GameOptions::default());
+        room.players = (1..=players).map(player).collect();
+        room
+    }
+
+    fn bot(color: usize) -> PlayerInRoom {
+        PlayerInRoom {
+            force_start: true,
+            bot: Some(BotDifficulty::Normal),
+            ..player(color)
+        }
+    }
+
+    fn vote(room: &mut Room, votes: &[usize]) -> bool {
+        for (i, player) in room.players.iter_mut().enumerate() {
+            player.force_start = votes.contains(&i);
+        }
+        room.update_votes()
+    }
+
+    #[test]
+    fn enough_votes_start_the_game() {
+        let mut room = room(4);
+        let needed = FORCE_START_OK[4] as usize;
+        assert!(!vote(&mut room, &(0..needed - 1).collect::<Vec<_>>()));
+        assert_eq!(room.force_start_num, needed - 1);
+        assert!(vote(&mut room, &(0..needed).collect::<Vec<_>>()));
+        assert_eq!(room.force_start_num, needed);
+    }
+
+    #[test]
+    fn full_rooms_start_without_votes() {
+        let mut full = room(2);
+        full.game_options.max_players = 2;
+        assert!(vote(&mut full, &[]));
+
+        // A full room of one can't play against anyone.
+        let mut alone = room(1);
+        alone.game_options.max_players = 1;
+        assert!(!vote(&mut alone, &[]));
+    }
+
+    #[test]
+    fn spectators_do_not_vote() {
+        let mut room = room(3);
+        room.players[2].set_spectate();
+        assert!(!vote(&mut room, &[0, 2]));
+        assert_eq!(room.force_start_num, 1);
+        assert!(vote(&mut room, &[0, 1, 2]));
+        assert_eq!(room.force_start_num, 2);
+    }
+
+    #[test]
+    fn bots_alone_never_start_a_game() {
+        let mut room = room(0);
+        room.players = vec![bot(1), bot(2)];
+        assert!(!room.update_votes());
+        assert_eq!(room.force_start_num, 2);
+
+        room.players.push(PlayerInRoom {
+            force_start: true,
+            ..player(3)
+        });
+        assert!(room.update_votes());
+    }
+
+    #[test]
+    fn public_room_hides_the_seed() {
+        let mut room = room(2);
+        room.game_options.seed = Some(123456789);
+        let public = serde_json::to_string(&room.to_public()).unwrap();
+        assert!(!public.contains("123456789"));
+        assert!(public.contains("\"seed_set\":true"));
+    }
+
+    #[test]
+    fn rooms_follow_the_lifecycle() {
+        use RoomState::*;
+        let allowed = [
+            (Lobby, Countdown),
+            (Lobby, Playing),
+            (Countdown, Lobby),
+            (Countdown, Playing),
+            (Playing, Finished),
+            (Finished, Lobby),
+        ];
+        for from in [Lobby, Countdown, Playing, Finished] {
+            for to in [Lobby, Countdown, Playing, Finished] {
+                assert_eq!(from.can_become(to), allowed.contains(&(from, to)));
+            }
+        }
+
+        let mut room = room(2);
+        assert!(room.set_state(Finished).is_err());
+        assert_eq!(room.state, Lobby);
+        room.set_state(Countdown).unwrap();
+        room.set_state(Playing).unwrap();
+        assert!(room.set_state(Lobby).is_err());
+        assert_eq!(room.state, Playing);
+    }
+
+    #[test]
+    fn tick_only_plays_running_games() {
+        let mut room = room(1);
+        room.map = blank_map(4, 4);
+        assert!(room.tick().is_empty());
+        assert_eq!((room.turn, room.state), (0, RoomState::Lobby));
+
+        room.set_state(RoomState::Playing).unwrap();
+        assert!(matches!(room.tick().as_slice(), [TurnEvent::GameOver(_)]));
+        assert_eq!((room.turn, room.state), (1, RoomState::Finished));
+        assert!(room.tick().is_empty());
+        assert_eq!(room.turn, 1);
+    }
+
+    #[test]
+    fn queue_commands_need_a_running_game() {
+        let mut room = room(2);
+        room.turn = 500;
+        assert!(room.apply_command(0, Command::ClearQueue).is_err());
+        assert!(room.apply_command(0, Command::PopQueue).is_err());
+        assert_eq!(room.players[0].last_operate_turn, 0);
+    }
+
+    #[test]
+    fn afk_check_survives_a_stale_last_command() {
+        let mut room = room(2);
+        room.map = blank_map(4, 4);
+        room.state = RoomState::Playing;
+        room.turn = 1;
+        room.players[0].last_operate_turn = 500;
+
+        assert!(room.check_afk().is_empty());
+        assert!(!room.players[0].is_dead);
+    }
+}
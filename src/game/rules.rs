@@ -0,0 +1,152 @@
+use super::{block::TileType, constants::LAND_GROWTH_INTERVAL, map_generator::Map};
+
+// Applies the per-turn economy to the map:
+// - kings and owned cities gain 1 unit every turn
+// - all other owned land gains 1 unit every LAND_GROWTH_INTERVAL turns
+// - owned swamps lose 1 unit every turn and turn neutral once empty
+// Land of the `inert` colors, players who gave up, doesn't change at all.
+pub fn apply_growth(map: &mut Map, turn: u32, inert: &[i16]) {
+    let land_growth = turn > 0 && turn.is_multiple_of(LAND_GROWTH_INTERVAL);
+
+    for block in map
+        .iter_mut()
+        .flatten()
+        .filter(|x| x.color != 0 && !inert.contains(&x.color))
+    {
+        match block.tile_type {
+            TileType::King | TileType::City => block.unit += 1,
+            TileType::Swamp => {
+                block.unit -= 1;
+                if block.unit <= 0 {
+                    block.unit = 0;
+                    block.dominated_by(0, 0);
+                }
+            }
+            _ if land_growth => block.unit += 1,
+            _ => {}
+        }
+    }
+}
+
+// Hands all of the victim's land to the captor. The captured king at `king` becomes a city.
+pub fn capture_king(
+    map: &mut Map,
+    (x, y): (usize, usize),
+    victim: i16,
+    captor: i16,
+    captor_team: i16,
+) {
+    map[x][y].tile_type = TileType::City;
+    for block in map.iter_mut().flatten().filter(|x| x.color == victim) {
+        block.dominated_by(captor, captor_team);
+    }
+}
+
+// Gives up the land of a surrendering player. With `neutral` their kings become neutral
+// cities and all their land turns grey, otherwise it is left to them and stays inert.
+pub fn surrender(map: &mut Map, color: i16, neutral: bool) {
+    if !neutral {
+        return;
+    }
+    for block in map.iter_mut().flatten().filter(|x| x.color == color) {
+        if block.tile_type == TileType::King {
+            block.tile_type = TileType::City;
+        }
+        block.dominated_by(0, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{block::Block, map_generator::blank_map};
+
+    fn owned(map: &mut Map, (x, y): (usize, usize), tile_type: TileType, unit: i64, color: i16) {
+        map[x][y] = Block::new(x as i32, y as i32, tile_type, unit);
+        map[x][y].dominated_by(color, color);
+    }
+
+    #[test]
+    fn kings_and_cities_grow_every_turn() {
+        let mut map = blank_map(3, 3);
+        owned(&mut map, (0, 0), TileType::King, 1, 1);
+        owned(&mut map, (1, 1), TileType::City, 40, 2);
+        map[2][2] = Block::new(2, 2, TileType::City, 40);
+
+        for turn in 1..=3 {
+            apply_growth(&mut map, turn, &[]);
+        }
+        assert_eq!(map[0][0].unit, 4);
+        assert_eq!(map[1][1].unit, 43);
+        // Neutral cities don't produce.
+        assert_eq!(map[2][2].unit, 40);
+    }
+
+    #[test]
+    fn land_grows_every_interval() {
+        let mut map = blank_map(2, 2);
+        owned(&mut map, (0, 0), TileType::Plain, 5, 1);
+        map[1][1].unit = 5;
+
+        apply_growth(&mut map, LAND_GROWTH_INTERVAL - 1, &[]);
+        assert_eq!(map[0][0].unit, 5);
+        apply_growth(&mut map, LAND_GROWTH_INTERVAL, &[]);
+        assert_eq!(map[0][0].unit, 6);
+        assert_eq!(map[1][1].unit, 5);
+    }
+
+    #[test]
+    fn swamps_decay_to_neutral() {
+        let mut map = blank_map(1, 1);
+        owned(&mut map, (0, 0), TileType::Swamp, 2, 1);
+
+        apply_growth(&mut map, 1, &[]);
+        assert_eq!((map[0][0].unit, map[0][0].color), (1, 1));
+        apply_growth(&mut map, 2, &[]);
+        assert_eq!((map[0][0].unit, map[0][0].color, map[0][0].team), (0, 0, 0));
+        apply_growth(&mut map, 3, &[]);
+        assert_eq!(map[0][0].unit, 0);
+    }
+
+    #[test]
+    fn inert_colors_are_left_alone() {
+        let mut map = blank_map(3, 1);
+        owned(&mut map, (0, 0), TileType::King, 10, 1);
+        owned(&mut map, (1, 0), TileType::Swamp, 1, 1);
+        owned(&mut map, (2, 0), TileType::Plain, 3, 1);
+
+        apply_growth(&mut map, LAND_GROWTH_INTERVAL, &[1]);
+        assert_eq!(map[0][0].unit, 10);
+        assert_eq!((map[1][0].unit, map[1][0].color), (1, 1));
+        assert_eq!(map[2][0].unit, 3);
+    }
+
+    #[test]
+    fn captured_land_goes_to_the_captor() {
+        let mut map = blank_map(3, 1);
+        owned(&mut map, (0, 0), TileType::King, 1, 1);
+        owned(&mut map, (1, 0), TileType::Plain, 4, 1);
+        owned(&mut map, (2, 0), TileType::King, 9, 2);
+
+        capture_king(&mut map, (0, 0), 1, 2, 5);
+        assert!(map[0][0].tile_type == TileType::City);
+        assert!(map.iter().flatten().all(|x| x.color == 2));
+        assert_eq!((map[1][0].unit, map[1][0].team), (4, 5));
+        assert!(map[2][0].tile_type == TileType::King);
+    }
+
+    #[test]
+    fn surrendered_land_turns_neutral_or_stays() {
+        let mut map = blank_map(2, 1);
+        owned(&mut map, (0, 0), TileType::King, 7, 1);
+        owned(&mut map, (1, 0), TileType::Plain, 3, 1);
+
+        surrender(&mut map, 1, false);
+        assert!(map.iter().flatten().all(|x| x.color == 1));
+
+        surrender(&mut map, 1, true);
+        assert!(map[0][0].tile_type == TileType::City);
+        assert!(map.iter().flatten().all(|x| x.color == 0 && x.team == 0));
+        assert_eq!(map[0][0].unit, 7);
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    collections::VecDeque,
+    ops::{Deref, DerefMut},
+};
+
+use serde::Serialize;
+use socketioxide::socket::Sid;
+
+use super::{
+    command::Move,
+    constants::CHAT_BACKLOG_SIZE,
+    diff::{diff_map, MapPatch},
+    map_generator::Map,
+    player_in_room::{MinifiedPlayer, PlayerInRoom},
+    replay::ReplayRecorder,
+    room::{GameOptions, LeaderboardEntry, PublicRoom, Room, RoomState},
+    view::{flatten_view, FlatView},
+};
+
+// What the server keeps about a player's connection.
+#[derive(Clone, Default, PartialEq)]
+pub struct Session {
+    pub socket_id: Sid,
+    // Connected through the /bot namespace, gets BotFrames instead of game updates.
+    pub compact: bool,
+    // The view sent with the last game update, live updates only carry the patch from it.
+    pub last_view: Map,
+}
+
+pub type Seat = PlayerInRoom<Session>;
+
+// A room as hosted by the server: the engine's Room, which it derefs to, plus the chat and
+// replay recording around the game.
+#[derive(Clone)]
+pub struct ServerRoom {
+    pub room: Room<Session>,
+    pub recorder: Option<ReplayRecorder>,
+    // Latest chat, in the same shape as the "message" event.
+    pub messages: VecDeque<(MinifiedPlayer, String)>,
+}
+
+// What one player receives every turn. The first patch of a game is relative to a blank
+// width x height map, later ones to the previous update.
+#[derive(Serialize)]
+pub struct GameUpdate {
+    turn: u32,
+    width: usize,
+    height: usize,
+    patch: MapPatch,
+    queue: VecDeque<Move>,
+}
+
+// What a player on the /bot namespace receives every turn: always the full view.
+#[derive(Serialize)]
+pub struct BotFrame {
+    turn: u32,
+    width: usize,
+    height: usize,
+    #[serde(flatten)]
+    view: FlatView,
+    queue_len: usize,
+}
+
+// Everything a reconnecting player needs to catch up. `update` is only set while a game
+// is running and its patch is relative to a blank map.
+#[derive(Serialize)]
+pub struct Resync {
+    pub room_id: String,
+    room: PublicRoom,
+    update: Option<GameUpdate>,
+    leaderboard: Vec<LeaderboardEntry>,
+    messages: VecDeque<(MinifiedPlayer, String)>,
+}
+
+impl Deref for ServerRoom {
+    type Target = Room<Session>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.room
+    }
+}
+
+impl DerefMut for ServerRoom {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.room
+    }
+}
+
+impl ServerRoom {
+    pub fn new(game_options: GameOptions) -> Self {
+        ServerRoom {
+            room: Room::new(game_options),
+            recorder: None,
+            messages: VecDeque::new(),
+        }
+    }
+
+    pub fn push_message(&mut self, sender: MinifiedPlayer, content: String) {
+        if self.messages.len() == CHAT_BACKLOG_SIZE {
+            self.messages.pop_front();
+        }
+        self.messages.push_back((sender, content));
+    }
+
+    // Records the map of the turn that was just played, if the game is being recorded.
+    pub fn record_turn(&mut self) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.record_turn(self.room.turn, &self.room.map);
+        }
+    }
+
+    // Full state for the player at `index`, whose next updates continue from it. Bots get
+    // their whole view with the next frame anyway, so they get no update.
+    pub fn resync(&mut self, room_id: String, index: usize) -> Resync {
+        let update = if self.state == RoomState::Playing && !self.players[index].session.compact {
+            let view = self.player_view(&self.players[index]);
+            let player = &mut self.room.players[index];
+            let update = GameUpdate {
+                turn: self.room.turn,
+                width: view.len(),
+                height: view.first().map_or(0, |x| x.len()),
+                patch: diff_map(&Vec::new(), &view),
+                queue: player.queue.clone(),
+            };
+            player.session.last_view = view;
+            Some(update)
+        } else {
+            None
+        };
+
+        Resync {
+            room_id,
+            room: self.to_public(),
+            update,
+            leaderboard: self.leaderboard(),
+            messages: self.messages.clone(),
+        }
+    }
+
+    pub fn game_updates(&mut self) -> Vec<(Sid, GameUpdate)> {
+        let views: Vec<Map> = self
+            .players
+            .iter()
+            .filter(|x| !x.session.compact)
+            .map(|x| self.player_view(x))
+            .collect();
+
+        let turn = self.room.turn;
+        self.room
+            .players
+            .iter_mut()
+            .filter(|x| !x.session.compact)
+            .zip(views)
+            .map(|(player, view)| {
+                let update = GameUpdate {
+                    turn,
+                    width: view.len(),
+                    height: view.first().map_or(0, |x| x.len()),
+                    patch: diff_map(&player.session.last_view, &view),
+                    queue: player.queue.clone(),
+                };
+                player.session.last_view = view;
+                (player.session.socket_id, update)
+            })
+            .collect()
+    }
+
+    pub fn bot_frames(&self) -> Vec<(Sid, BotFrame)> {
+        self.players
+            .iter()
+            .filter(|x| x.session.compact)
+            .map(|player| {
+                let view = self.player_view(player);
+                let frame = BotFrame {
+                    turn: self.turn,
+                    width: view.len(),
+                    height: view.first().map_or(0, |x| x.len()),
+                    view: flatten_view(&view),
+                    queue_len: player.queue.len(),
+                };
+                (player.session.socket_id, frame)
+            })
+            .collect()
+    }
+}
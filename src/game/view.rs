@@ -0,0 +1,170 @@
+use serde::Serialize;
+
+use super::{
+    block::{Block, TileType},
+    map_generator::Map,
+    room::GameOptions,
+};
+
+// Builds what a player of `team` may see of the map. Vision is shared within a team, so tiles
+// outside the 8-neighbourhood of the team's land are masked: mountains and cities become
+// Obstacle, everything else becomes Fog.
+pub fn player_view(map: &Map, options: &GameOptions, team: i16, full_vision: bool) -> Map {
+    if full_vision || !options.fog_of_war {
+        return map.clone();
+    }
+
+    let width = map.len();
+    let height = map.first().map_or(0, |x| x.len());
+    let mut visible = vec![vec![false; height]; width];
+    for block in map.iter().flatten().filter(|x| x.is_allied(team)) {
+        let (x, y) = (block.x as usize, block.y as usize);
+        for column in &mut visible[x.saturating_sub(1)..=usize::min(x + 1, width - 1)] {
+            for cell in &mut column[y.saturating_sub(1)..=usize::min(y + 1, height - 1)] {
+                *cell = true;
+            }
+        }
+    }
+
+    map.iter()
+        .map(|column| {
+            column
+                .iter()
+                .map(|block| {
+                    let revealed = visible[block.x as usize][block.y as usize]
+                        || block.is_always_revealed
+                        || (options.reveal_king && block.tile_type == TileType::King);
+                    if revealed {
+                        *block
+                    } else {
+                        masked(block)
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn masked(block: &Block) -> Block {
+    let tile_type = match block.tile_type {
+        TileType::Mountain | TileType::City => TileType::Obstacle,
+        _ => TileType::Fog,
+    };
+    Block::new(block.x, block.y, tile_type, 0)
+}
+
+// A view as flat arrays indexed by x * height + y, for bots which don't want to parse blocks.
+#[derive(Serialize)]
+pub struct FlatView {
+    tiles: Vec<u8>,
+    units: Vec<i64>,
+    colors: Vec<i16>,
+}
+
+pub fn flatten_view(view: &Map) -> FlatView {
+    let blocks = view.iter().flatten();
+    FlatView {
+        tiles: blocks.clone().map(|x| x.tile_type as u8).collect(),
+        units: blocks.clone().map(|x| x.unit).collect(),
+        colors: blocks.map(|x| x.color).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::map_generator::blank_map;
+
+    fn owned(map: &mut Map, (x, y): (usize, usize), tile_type: TileType, color: i16, team: i16) {
+        map[x][y].tile_type = tile_type;
+        map[x][y].unit = 5;
+        map[x][y].dominated_by(color, team);
+    }
+
+    // Player 1 of team 1 owns the corner (0, 0), the rest of a 5x5 map is far away.
+    fn corner_map() -> Map {
+        let mut map = blank_map(5, 5);
+        owned(&mut map, (0, 0), TileType::Plain, 1, 1);
+        map
+    }
+
+    #[test]
+    fn only_the_eight_neighbourhood_is_visible() {
+        let mut map = corner_map();
+        owned(&mut map, (1, 1), TileType::Plain, 2, 2);
+        owned(&mut map, (2, 2), TileType::Plain, 2, 2);
+        let view = player_view(&map, &GameOptions::default(), 1, false);
+
+        assert_eq!(view[1][1], map[1][1]);
+        assert_eq!(view[0][1], map[0][1]);
+        for (x, y) in [(2, 0), (0, 2), (2, 2)] {
+            assert!(view[x][y].tile_type == TileType::Fog);
+            assert_eq!((view[x][y].unit, view[x][y].color), (0, 0));
+        }
+    }
+
+    #[test]
+    fn hidden_mountains_and_cities_are_obstacles() {
+        let mut map = corner_map();
+        map[4][4].tile_type = TileType::Mountain;
+        map[3][4].tile_type = TileType::City;
+        map[3][4].unit = 40;
+        map[4][3].tile_type = TileType::Swamp;
+        owned(&mut map, (4, 0), TileType::King, 2, 2);
+        let view = player_view(&map, &GameOptions::default(), 1, false);
+
+        assert!(view[4][4].tile_type == TileType::Obstacle);
+        assert!(view[3][4].tile_type == TileType::Obstacle);
+        assert_eq!(view[3][4].unit, 0);
+        assert!(view[4][3].tile_type == TileType::Fog);
+        assert!(view[4][0].tile_type == TileType::Fog);
+        assert_eq!(view[4][0].color, 0);
+    }
+
+    #[test]
+    fn revealed_tiles_show_through_the_fog() {
+        let mut map = corner_map();
+        map[4][4].tile_type = TileType::City;
+        map[4][4].is_always_revealed = true;
+        owned(&mut map, (4, 0), TileType::King, 2, 2);
+
+        let view = player_view(&map, &GameOptions::default(), 1, false);
+        assert_eq!(view[4][4], map[4][4]);
+        assert!(view[4][0].tile_type == TileType::Fog);
+
+        let options = GameOptions {
+            reveal_king: true,
+            ..Default::default()
+        };
+        let view = player_view(&map, &options, 1, false);
+        assert_eq!(view[4][0], map[4][0]);
+    }
+
+    #[test]
+    fn teams_share_their_vision() {
+        let mut map = corner_map();
+        owned(&mut map, (4, 4), TileType::Plain, 2, 1);
+        owned(&mut map, (3, 3), TileType::Plain, 3, 2);
+
+        let view = player_view(&map, &GameOptions::default(), 1, false);
+        assert_eq!(view[3][3], map[3][3]);
+
+        // Neutral land has team 0 but gives nobody vision.
+        let view = player_view(&map, &GameOptions::default(), 0, false);
+        assert!(view.iter().flatten().all(|x| x.tile_type == TileType::Fog));
+    }
+
+    #[test]
+    fn everything_is_visible_without_fog() {
+        let mut map = corner_map();
+        map[4][4].tile_type = TileType::Mountain;
+        owned(&mut map, (4, 0), TileType::King, 2, 2);
+
+        let options = GameOptions {
+            fog_of_war: false,
+            ..Default::default()
+        };
+        assert_eq!(player_view(&map, &options, 1, false), map);
+        assert_eq!(player_view(&map, &GameOptions::default(), 1, true), map);
+    }
+}
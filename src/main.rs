@@ -4,7 +4,9 @@ mod prisma;
 mod routes;
 
 use axum::{extract::Extension, Router};
-use game::{handle_connection, RoomPoolStore};
+use game::{
+    handle_bot_connection, handle_connection, PlaybackStore, RoomPoolStore, BOT_NAMESPACE,
+};
 use prisma::PrismaClient;
 use socketioxide::SocketIo;
 use std::{env, sync::Arc};
@@ -23,11 +25,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let (layer, io) = SocketIo::builder()
         .with_state(db_socket)
-        .with_state(room_pool)
+        .with_state(room_pool.clone())
+        .with_state(PlaybackStore::default())
         .build_layer();
 
+    room_pool.bind_io(io.clone());
+
     // Register a handler for the default namespace
     io.ns("/", handle_connection);
+    io.ns(BOT_NAMESPACE, handle_bot_connection);
 
     let app = Router::new()
         .nest("/api", routes::create_route())
@@ -19,6 +19,7 @@ use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, sync::Arc, vec};
 use uuid::Uuid;
 
+use crate::game::{replay_snapshot, ReplaySnapshot};
 use crate::prisma::*;
 
 type PrismaState = Extension<Arc<PrismaClient>>;
@@ -28,9 +29,10 @@ type AppJsonResult<T> = AppResult<Json<T>>;
 /*
 
 /api/register => POST
+/api/api_key => POST
 /api/rooms => GET
 /api/create_room => POST
-/api/replays/:replay_id => GET
+/api/replays/:replay_id?turn= => GET
 /api/maps => GET
 /api/maps/new => GET
 /api/maps/best => GET
@@ -44,6 +46,7 @@ type AppJsonResult<T> = AppResult<Json<T>>;
 pub fn create_route() -> Router {
     Router::new()
         .route("/register", post(handle_player_register))
+        .route("/api_key", post(handle_api_key_create))
         .route("/replays/:replay_id", get(handle_replays_get))
         .route("/maps", get(handle_all_maps_get))
         .route("/maps/new", get(handle_new_maps_get))
@@ -113,18 +116,118 @@ async fn handle_player_register(
     }
 }
 
+#[derive(Deserialize)]
+struct ApiKeyRequest {
+    username: String,
+    player_id: String,
+}
+
+#[derive(Serialize)]
+struct ApiKeyResponse {
+    api_key: String,
+}
+
+// Issues a new api key for bots to log in as this player, replacing the previous one.
+#[debug_handler]
+async fn handle_api_key_create(
+    Extension(db): PrismaState,
+    Json(ApiKeyRequest {
+        username,
+        player_id,
+    }): Json<ApiKeyRequest>,
+) -> AppJsonResult<ApiKeyResponse> {
+    let player = db
+        .player()
+        .find_unique(player::id::equals(player_id.clone()))
+        .exec()
+        .await?
+        .ok_or(AppError::NotFound)?;
+    if player.username != username {
+        return Err(AppError::NotFound);
+    }
+
+    let api_key = Uuid::new_v4().to_string();
+    db.player()
+        .update(
+            player::id::equals(player_id),
+            vec![player::api_key::set(Some(api_key.clone()))],
+        )
+        .exec()
+        .await?;
+
+    Ok(Json::from(ApiKeyResponse { api_key }))
+}
+
+// With `?turn=` only the map at that turn is returned, rebuilt from the closest keyframe.
 #[debug_handler]
 async fn handle_replays_get(
     Extension(db): PrismaState,
     Path(replay_id): Path<Uuid>,
-) -> AppJsonResult<replay::Data> {
+    Query(params): Query<HashMap<String, String>>,
+) -> AppResult<Response> {
+    if let Some(turn) = params.get("turn") {
+        let turn: u32 = turn.parse().map_err(|_| AppError::BadRequest)?;
+        return Ok(
+            Json::from(get_replay_snapshot(&db, replay_id.to_string(), turn).await?)
+                .into_response(),
+        );
+    }
+
     let replay = db
         .replay()
         .find_unique(replay::id::equals(replay_id.to_string()))
+        .with(replay::game_records::fetch(vec![]).order_by(map_diff::turn::order(Direction::Asc)))
+        .with(replay::message_records::fetch(vec![]).order_by(message::turn::order(Direction::Asc)))
+        .exec()
+        .await?;
+
+    Ok(Json::from(replay.ok_or(AppError::NotFound)?).into_response())
+}
+
+async fn get_replay_snapshot(
+    db: &PrismaClient,
+    replay_id: String,
+    turn: u32,
+) -> AppResult<ReplaySnapshot> {
+    let replay = db
+        .replay()
+        .find_unique(replay::id::equals(replay_id.clone()))
+        .exec()
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let keyframe = db
+        .map_keyframe()
+        .find_first(vec![
+            map_keyframe::replay_id::equals(replay_id.clone()),
+            map_keyframe::turn::lte(turn as i32),
+        ])
+        .order_by(map_keyframe::turn::order(Direction::Desc))
+        .exec()
+        .await?;
+    // Without a keyframe every diff is needed, including the turn 0 one with the initial map.
+    let from = keyframe.as_ref().map(|x| x.turn);
+    let mut filters = vec![
+        map_diff::replay_id::equals(replay_id),
+        map_diff::turn::lte(turn as i32),
+    ];
+    if let Some(from) = from {
+        filters.push(map_diff::turn::gt(from));
+    }
+    let diffs = db
+        .map_diff()
+        .find_many(filters)
+        .order_by(map_diff::turn::order(Direction::Asc))
         .exec()
         .await?;
 
-    Ok(Json::from(replay.unwrap()))
+    replay_snapshot(
+        replay.map_width as usize,
+        replay.map_height as usize,
+        turn,
+        keyframe.into_iter().map(|x| (x.turn, x.data)).collect(),
+        diffs.into_iter().map(|x| (x.turn, x.data)).collect(),
+    )
+    .map_err(|_| AppError::Corrupted)
 }
 
 custom_map_data::select!(map_selected {
@@ -368,6 +471,9 @@ async fn handle_star_map(
 enum AppError {
     PrismaError(QueryError),
     NotFound,
+    BadRequest,
+    // Stored data that can't be parsed back.
+    Corrupted,
 }
 
 impl From<QueryError> for AppError {
@@ -388,6 +494,8 @@ impl IntoResponse for AppError {
             }
             AppError::PrismaError(_) => StatusCode::BAD_REQUEST,
             AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::BadRequest => StatusCode::BAD_REQUEST,
+            AppError::Corrupted => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
         status.into_response()